@@ -1,6 +1,7 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
+    /* The last 16K is left for settings, see src/storage.rs */
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 16K
     RAM   : ORIGIN = 0x20000000, LENGTH = 256K
 }
 
@@ -0,0 +1,102 @@
+//! The display backlight, set to the configured brightness while the buttons are in use.
+//!
+//! After the idle timeout without a button press the backlight dims, and a while later it
+//! switches off. A button press or an [`alarm`] lights it again.
+
+use crate::config;
+use embassy_rp::pwm::{Pwm, SetDutyCycle};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal};
+use embassy_time::{Duration, Instant, Timer};
+
+/// Brightness while dimmed, as a percentage of the configured brightness.
+const DIM_PERCENT: u32 = 25;
+/// How long the backlight stays dimmed before switching off.
+const DIM_TIME: Duration = Duration::from_secs(60);
+
+/// Time taken to change between brightness levels.
+pub(crate) const FADE_TIME: Duration = Duration::from_millis(500);
+const FADE_STEPS: u32 = 20;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct BacklightConfig {
+    /// Percentage of full brightness while in use, from 1 to 100.
+    pub(crate) brightness: u8,
+    /// Time without a button press before the backlight dims, it stays lit if `None`.
+    pub(crate) idle_timeout: Option<Duration>,
+}
+
+static ALARM: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Light the display to draw attention to an event, as a button press would.
+pub(crate) fn alarm() {
+    ALARM.signal(());
+}
+
+/// Wait for an [`alarm`]. Only one task may wait at a time.
+pub(crate) async fn alarmed() {
+    ALARM.wait().await
+}
+
+pub(crate) struct Backlight<'d> {
+    pwm: Pwm<'d>,
+    /// Current brightness in percent.
+    level: u8,
+    last_activity: Instant,
+}
+
+impl<'d> Backlight<'d> {
+    /// Take over the backlight PWM output, which starts off.
+    pub(crate) fn new(pwm: Pwm<'d>) -> Self {
+        let mut backlight = Self {
+            pwm,
+            level: 0,
+            last_activity: Instant::now(),
+        };
+        backlight.set(0);
+        backlight
+    }
+
+    pub(crate) fn is_off(&self) -> bool {
+        self.level == 0
+    }
+
+    /// Note that a button was pressed or an alarm raised, restarting the idle timeout.
+    pub(crate) fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    /// Fade to the brightness the settings and the time since the last activity call for.
+    pub(crate) async fn update(&mut self, fade_time: Duration) {
+        let config = config::get().backlight;
+        let idle = self.last_activity.elapsed();
+
+        let level = match config.idle_timeout {
+            Some(timeout) if idle >= timeout + DIM_TIME => 0,
+            Some(timeout) if idle >= timeout => {
+                (config.brightness as u32 * DIM_PERCENT / 100).max(1) as u8
+            }
+            _ => config.brightness,
+        };
+
+        if level != self.level {
+            self.fade_to(level, fade_time).await;
+        }
+    }
+
+    /// Change the brightness in even steps over `duration`.
+    pub(crate) async fn fade_to(&mut self, level: u8, duration: Duration) {
+        let from = self.level as i32;
+        let to = level.min(100) as i32;
+        let step_time = duration / FADE_STEPS;
+
+        for step in 1..=FADE_STEPS as i32 {
+            self.set((from + (to - from) * step / FADE_STEPS as i32) as u8);
+            Timer::after(step_time).await;
+        }
+    }
+
+    fn set(&mut self, level: u8) {
+        let _ = self.pwm.set_duty_cycle_percent(level);
+        self.level = level;
+    }
+}
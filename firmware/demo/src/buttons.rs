@@ -1,9 +1,20 @@
 use crate::ButtonResources;
-use defmt::info;
+use defmt::{debug, Format};
 use embassy_futures::select::{select3, Either3};
 use embassy_rp::gpio::{Input, Pull};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
 use embassy_time::Timer;
 
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum Button {
+    A,
+    B,
+    C,
+}
+
+/// Button presses, in the order they happened.
+pub(crate) static PRESSES: Channel<CriticalSectionRawMutex, Button, 4> = Channel::new();
+
 #[embassy_executor::task]
 pub(super) async fn task(r: ButtonResources) {
     let mut a = Input::new(r.a_pin, Pull::Up);
@@ -11,17 +22,20 @@ pub(super) async fn task(r: ButtonResources) {
     let mut c = Input::new(r.c_pin, Pull::Up);
 
     loop {
-        match select3(
+        let button = match select3(
             a.wait_for_falling_edge(),
             b.wait_for_falling_edge(),
             c.wait_for_falling_edge(),
         )
         .await
         {
-            Either3::First(_) => info!("A pressed"),
-            Either3::Second(_) => info!("B pressed"),
-            Either3::Third(_) => info!("C pressed"),
-        }
+            Either3::First(_) => Button::A,
+            Either3::Second(_) => Button::B,
+            Either3::Third(_) => Button::C,
+        };
+
+        debug!("{} pressed", button);
+        PRESSES.send(button).await;
 
         Timer::after_millis(250).await;
     }
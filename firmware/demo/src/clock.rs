@@ -0,0 +1,85 @@
+//! Wall clock time in UTC, kept as an offset from [`Instant`] that is set by [`sntp`].
+//!
+//! [`sntp`]: crate::sntp
+
+use core::{cell::Cell, fmt};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+
+/// Microseconds since the Unix epoch at which [`Instant`] was zero, `None` until the clock is set.
+static BOOT_TIME: Mutex<CriticalSectionRawMutex, Cell<Option<u64>>> = Mutex::new(Cell::new(None));
+
+/// A point in time, in microseconds since the Unix epoch.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct UtcTime(u64);
+
+impl UtcTime {
+    pub(crate) const fn from_unix_micros(micros: u64) -> Self {
+        Self(micros)
+    }
+
+    pub(crate) fn unix_secs(self) -> u64 {
+        self.0 / 1_000_000
+    }
+}
+
+/// RFC 3339 with milliseconds, such as `2024-01-31T23:59:59.123Z`.
+impl fmt::Display for UtcTime {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self.unix_secs();
+        let (year, month, day) = civil_from_days((secs / 86400) as i64);
+        write!(
+            f,
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year,
+            month,
+            day,
+            secs / 3600 % 24,
+            secs / 60 % 60,
+            secs % 60,
+            self.0 / 1000 % 1000
+        )
+    }
+}
+
+/// The current time, `None` if the clock has not been set since power on.
+pub(crate) fn now() -> Option<UtcTime> {
+    at(Instant::now())
+}
+
+/// The time at `instant`, `None` if the clock has not been set since power on.
+pub(crate) fn at(instant: Instant) -> Option<UtcTime> {
+    BOOT_TIME
+        .lock(Cell::get)
+        .map(|boot| UtcTime(boot + instant.as_micros()))
+}
+
+/// Whether the clock has been set, so [`now`] returns the time.
+pub(crate) fn is_valid() -> bool {
+    BOOT_TIME.lock(Cell::get).is_some()
+}
+
+/// Set the clock from the time at `instant`.
+pub(crate) fn set(time: UtcTime, instant: Instant) {
+    let boot = time.0.saturating_sub(instant.as_micros());
+    BOOT_TIME.lock(|c| c.set(Some(boot)));
+}
+
+/// Year, month and day of a number of days since the Unix epoch, in the proleptic Gregorian
+/// calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // Count from 0000-03-01 so leap days fall at the end of each year
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z.rem_euclid(146_097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+
+    (year, month, day)
+}
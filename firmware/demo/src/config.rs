@@ -0,0 +1,527 @@
+//! Settings that can be changed in the field, kept in flash.
+//!
+//! Settings are stored as a version byte followed by key, length and value entries. Missing keys
+//! take their factory default and unknown keys are skipped, so settings can be added without
+//! changing the version.
+
+use crate::{
+    backlight::BacklightConfig,
+    framing::{self, Framing},
+    poller::{DataType, Poll, RegisterType, WordOrder, MAX_POLLS},
+    rs485::{LineConfig, PORT_COUNT},
+    serial_server::ClientPolicy,
+    storage::{self, Storage, MAX_RECORD_SIZE},
+    syslog::{self, Severity, SyslogServer},
+};
+use core::cell::Cell;
+use defmt::{info, warn, Format};
+use embassy_net::{Ipv4Address, Ipv4Cidr};
+use embassy_rp::uart::{DataBits, Parity, StopBits};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+    once_lock::OnceLock,
+};
+use embassy_time::Duration;
+use heapless::Vec;
+
+/// Changed when the meaning of an existing key changes, records of any other version are ignored.
+const VERSION: u8 = 1;
+
+const KEY_MAC_ADDRESS: u8 = 0x01;
+const KEY_CLIENT_POLICY: u8 = 0x02;
+const KEY_IPV4_MODE: u8 = 0x03;
+const KEY_DHCP_TIMEOUT: u8 = 0x04;
+const KEY_STATIC_IPV4: u8 = 0x05;
+const KEY_MQTT_BROKER: u8 = 0x06;
+const KEY_SYSLOG: u8 = 0x07;
+const KEY_NTP_SERVER: u8 = 0x08;
+const KEY_BACKLIGHT: u8 = 0x09;
+/// Line settings of the first RS485 port, subsequent ports use the following keys.
+const KEY_PORT_LINE: u8 = 0x10;
+/// Framing of data received on the first RS485 port, subsequent ports use the following keys.
+const KEY_PORT_FRAMING: u8 = 0x20;
+/// First entry of the poll list, subsequent entries use the following keys.
+const KEY_POLL: u8 = 0x40;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Config {
+    /// Replaces the MAC address derived from the unique ID.
+    pub(crate) mac_address: Option<[u8; 6]>,
+    pub(crate) client_policy: ClientPolicy,
+    /// Line settings each RS485 port starts with.
+    pub(crate) ports: [LineConfig; PORT_COUNT],
+    /// How data received on each RS485 port is grouped into frames, it is passed on as it arrives
+    /// if not set.
+    pub(crate) framings: [Option<Framing>; PORT_COUNT],
+    pub(crate) ipv4_mode: Ipv4Mode,
+    /// How long to wait for a DHCP lease before using the static address.
+    pub(crate) dhcp_timeout: Duration,
+    /// Address used in static mode or when DHCP times out, a link-local address is used if not set.
+    pub(crate) static_ipv4: Option<StaticIpv4>,
+    /// Broker the MQTT client connects to, the client is idle if not set.
+    pub(crate) mqtt_broker: Option<MqttBroker>,
+    /// Server events are sent to, events are only logged locally if not set.
+    pub(crate) syslog: Option<SyslogServer>,
+    /// Server the clock is set from, the NTP pool is used if not set.
+    pub(crate) ntp_server: Option<Ipv4Address>,
+    pub(crate) backlight: BacklightConfig,
+    /// Modbus registers to read from devices on the RS485 ports.
+    pub(crate) polls: [Option<Poll>; MAX_POLLS],
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum Ipv4Mode {
+    Static,
+    Dhcp,
+}
+
+impl Ipv4Mode {
+    pub(crate) const ALL: [Ipv4Mode; 2] = [Ipv4Mode::Static, Ipv4Mode::Dhcp];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Ipv4Mode::Static => "static",
+            Ipv4Mode::Dhcp => "dhcp",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct StaticIpv4 {
+    pub(crate) address: Ipv4Cidr,
+    pub(crate) gateway: Option<Ipv4Address>,
+    pub(crate) dns_server: Option<Ipv4Address>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct MqttBroker {
+    pub(crate) address: Ipv4Address,
+    pub(crate) port: u16,
+}
+
+impl Config {
+    pub(crate) const fn factory_default() -> Self {
+        Self {
+            mac_address: None,
+            client_policy: ClientPolicy::Reject,
+            ports: [LineConfig::new(115200); PORT_COUNT],
+            framings: [None; PORT_COUNT],
+            ipv4_mode: Ipv4Mode::Dhcp,
+            dhcp_timeout: Duration::from_secs(30),
+            static_ipv4: None,
+            mqtt_broker: None,
+            syslog: None,
+            ntp_server: None,
+            backlight: BacklightConfig {
+                brightness: 100,
+                idle_timeout: Some(Duration::from_secs(60)),
+            },
+            polls: [None; MAX_POLLS],
+        }
+    }
+
+    fn encode(&self) -> Vec<u8, MAX_RECORD_SIZE> {
+        let mut record = Vec::new();
+        let _ = record.push(VERSION);
+
+        let mut entry = |key: u8, value: &[u8]| {
+            let _ = record.extend_from_slice(&[key, value.len() as u8]);
+            let _ = record.extend_from_slice(value);
+        };
+
+        if let Some(mac_address) = &self.mac_address {
+            entry(KEY_MAC_ADDRESS, mac_address);
+        }
+        entry(
+            KEY_CLIENT_POLICY,
+            &[match self.client_policy {
+                ClientPolicy::Reject => 0,
+                ClientPolicy::TakeOver => 1,
+                ClientPolicy::Share => 2,
+            }],
+        );
+        for (n, line) in self.ports.iter().enumerate() {
+            entry(KEY_PORT_LINE + n as u8, &encode_line(line));
+        }
+        for (n, framing) in self.framings.iter().enumerate() {
+            if framing.is_some() {
+                entry(KEY_PORT_FRAMING + n as u8, &framing::to_bytes(*framing));
+            }
+        }
+        entry(
+            KEY_IPV4_MODE,
+            &[match self.ipv4_mode {
+                Ipv4Mode::Static => 0,
+                Ipv4Mode::Dhcp => 1,
+            }],
+        );
+        entry(
+            KEY_DHCP_TIMEOUT,
+            &(self.dhcp_timeout.as_secs().min(u16::MAX as u64) as u16).to_le_bytes(),
+        );
+        if let Some(static_ipv4) = &self.static_ipv4 {
+            entry(KEY_STATIC_IPV4, &encode_static_ipv4(static_ipv4));
+        }
+        if let Some(mqtt_broker) = &self.mqtt_broker {
+            entry(KEY_MQTT_BROKER, &encode_mqtt_broker(mqtt_broker));
+        }
+        if let Some(syslog) = &self.syslog {
+            entry(KEY_SYSLOG, &encode_syslog(syslog));
+        }
+        if let Some(ntp_server) = &self.ntp_server {
+            entry(KEY_NTP_SERVER, &ntp_server.octets());
+        }
+        entry(KEY_BACKLIGHT, &encode_backlight(&self.backlight));
+        for (i, poll) in self.polls.iter().enumerate() {
+            if let Some(poll) = poll {
+                entry(KEY_POLL + i as u8, &encode_poll(poll));
+            }
+        }
+
+        record
+    }
+
+    /// Returns `None` if the record is malformed or of another version.
+    fn decode(record: &[u8]) -> Option<Self> {
+        let (&VERSION, mut entries) = record.split_first()? else {
+            return None;
+        };
+
+        let mut config = Self::factory_default();
+
+        while let [key, len, rest @ ..] = entries {
+            let (value, rest) = rest.split_at_checked(*len as usize)?;
+            entries = rest;
+
+            match *key {
+                KEY_MAC_ADDRESS => config.mac_address = Some(value.try_into().ok()?),
+                KEY_CLIENT_POLICY => {
+                    config.client_policy = match value {
+                        [0] => ClientPolicy::Reject,
+                        [1] => ClientPolicy::TakeOver,
+                        [2] => ClientPolicy::Share,
+                        _ => return None,
+                    }
+                }
+                KEY_IPV4_MODE => {
+                    config.ipv4_mode = match value {
+                        [0] => Ipv4Mode::Static,
+                        [1] => Ipv4Mode::Dhcp,
+                        _ => return None,
+                    }
+                }
+                KEY_DHCP_TIMEOUT => {
+                    let secs = u16::from_le_bytes(value.try_into().ok()?);
+                    config.dhcp_timeout = Duration::from_secs(secs as u64);
+                }
+                KEY_STATIC_IPV4 => config.static_ipv4 = Some(decode_static_ipv4(value)?),
+                KEY_MQTT_BROKER => config.mqtt_broker = Some(decode_mqtt_broker(value)?),
+                KEY_SYSLOG => config.syslog = Some(decode_syslog(value)?),
+                KEY_NTP_SERVER => {
+                    let octets: [u8; 4] = value.try_into().ok()?;
+                    config.ntp_server = Some(Ipv4Address::from(octets));
+                }
+                KEY_BACKLIGHT => config.backlight = decode_backlight(value)?,
+                key if (KEY_PORT_LINE..KEY_PORT_LINE + PORT_COUNT as u8).contains(&key) => {
+                    config.ports[(key - KEY_PORT_LINE) as usize] = decode_line(value)?;
+                }
+                key if (KEY_PORT_FRAMING..KEY_PORT_FRAMING + PORT_COUNT as u8).contains(&key) => {
+                    config.framings[(key - KEY_PORT_FRAMING) as usize] =
+                        framing::from_bytes(value.try_into().ok()?)?;
+                }
+                key if (KEY_POLL..KEY_POLL + MAX_POLLS as u8).contains(&key) => {
+                    config.polls[(key - KEY_POLL) as usize] = Some(decode_poll(value)?);
+                }
+                _ => {}
+            }
+        }
+
+        entries.is_empty().then_some(config)
+    }
+}
+
+/// Baud rate as a little endian `u32`, then data bits, parity (0 none, 1 odd, 2 even) and stop
+/// bits.
+fn encode_line(line: &LineConfig) -> [u8; 7] {
+    let mut value = [0; 7];
+    value[..4].copy_from_slice(&line.baudrate.to_le_bytes());
+    value[4] = match line.data_bits {
+        DataBits::DataBits5 => 5,
+        DataBits::DataBits6 => 6,
+        DataBits::DataBits7 => 7,
+        DataBits::DataBits8 => 8,
+    };
+    value[5] = match line.parity {
+        Parity::ParityNone => 0,
+        Parity::ParityOdd => 1,
+        Parity::ParityEven => 2,
+    };
+    value[6] = match line.stop_bits {
+        StopBits::STOP1 => 1,
+        StopBits::STOP2 => 2,
+    };
+    value
+}
+
+fn decode_line(value: &[u8]) -> Option<LineConfig> {
+    let [b0, b1, b2, b3, data_bits, parity, stop_bits] = *value else {
+        return None;
+    };
+
+    let baudrate = u32::from_le_bytes([b0, b1, b2, b3]);
+    if baudrate == 0 {
+        return None;
+    }
+
+    Some(LineConfig {
+        baudrate,
+        data_bits: match data_bits {
+            5 => DataBits::DataBits5,
+            6 => DataBits::DataBits6,
+            7 => DataBits::DataBits7,
+            8 => DataBits::DataBits8,
+            _ => return None,
+        },
+        parity: match parity {
+            0 => Parity::ParityNone,
+            1 => Parity::ParityOdd,
+            2 => Parity::ParityEven,
+            _ => return None,
+        },
+        stop_bits: match stop_bits {
+            1 => StopBits::STOP1,
+            2 => StopBits::STOP2,
+            _ => return None,
+        },
+    })
+}
+
+/// Address, prefix length, gateway and DNS server, with an unset gateway or DNS server stored as
+/// 0.0.0.0.
+fn encode_static_ipv4(static_ipv4: &StaticIpv4) -> [u8; 13] {
+    let unspecified = Ipv4Address::UNSPECIFIED;
+
+    let mut value = [0; 13];
+    value[..4].copy_from_slice(&static_ipv4.address.address().octets());
+    value[4] = static_ipv4.address.prefix_len();
+    value[5..9].copy_from_slice(&static_ipv4.gateway.unwrap_or(unspecified).octets());
+    value[9..13].copy_from_slice(&static_ipv4.dns_server.unwrap_or(unspecified).octets());
+    value
+}
+
+fn decode_static_ipv4(value: &[u8]) -> Option<StaticIpv4> {
+    let value: &[u8; 13] = value.try_into().ok()?;
+
+    let address = |offset: usize| {
+        let octets: [u8; 4] = value[offset..offset + 4].try_into().ok()?;
+        Some(Ipv4Address::from(octets)).filter(|a| !a.is_unspecified())
+    };
+
+    let prefix_len = value[4];
+    if prefix_len > 32 {
+        return None;
+    }
+
+    Some(StaticIpv4 {
+        address: Ipv4Cidr::new(address(0)?, prefix_len),
+        gateway: address(5),
+        dns_server: address(9),
+    })
+}
+
+/// Address followed by the port as a little endian `u16`.
+fn encode_mqtt_broker(mqtt_broker: &MqttBroker) -> [u8; 6] {
+    let mut value = [0; 6];
+    value[..4].copy_from_slice(&mqtt_broker.address.octets());
+    value[4..].copy_from_slice(&mqtt_broker.port.to_le_bytes());
+    value
+}
+
+fn decode_mqtt_broker(value: &[u8]) -> Option<MqttBroker> {
+    let [a0, a1, a2, a3, p0, p1] = *value else {
+        return None;
+    };
+
+    Some(MqttBroker {
+        address: Ipv4Address::new(a0, a1, a2, a3),
+        port: u16::from_le_bytes([p0, p1]),
+    })
+}
+
+/// Brightness in percent followed by the idle timeout in seconds as a little endian `u16`, zero
+/// meaning the backlight stays lit.
+fn encode_backlight(backlight: &BacklightConfig) -> [u8; 3] {
+    let secs = backlight.idle_timeout.map_or(0, |timeout| {
+        timeout.as_secs().clamp(1, u16::MAX as u64) as u16
+    });
+
+    let mut value = [0; 3];
+    value[0] = backlight.brightness;
+    value[1..].copy_from_slice(&secs.to_le_bytes());
+    value
+}
+
+fn decode_backlight(value: &[u8]) -> Option<BacklightConfig> {
+    let [brightness @ 1..=100, s0, s1] = *value else {
+        return None;
+    };
+
+    let idle_timeout = match u16::from_le_bytes([s0, s1]) {
+        0 => None,
+        secs => Some(Duration::from_secs(secs as u64)),
+    };
+
+    Some(BacklightConfig {
+        brightness,
+        idle_timeout,
+    })
+}
+
+/// Address, port as a little endian `u16` and the minimum severity as its RFC 5424 value. A port
+/// of zero means the standard syslog port.
+fn encode_syslog(syslog: &SyslogServer) -> [u8; 7] {
+    let mut value = [0; 7];
+    value[..4].copy_from_slice(&syslog.address.octets());
+    value[4..6].copy_from_slice(&syslog.port.to_le_bytes());
+    value[6] = syslog.min_severity as u8;
+    value
+}
+
+fn decode_syslog(value: &[u8]) -> Option<SyslogServer> {
+    let [a0, a1, a2, a3, p0, p1, severity] = *value else {
+        return None;
+    };
+
+    let port = match u16::from_le_bytes([p0, p1]) {
+        0 => syslog::DEFAULT_PORT,
+        port => port,
+    };
+
+    Some(SyslogServer {
+        address: Ipv4Address::new(a0, a1, a2, a3),
+        port,
+        min_severity: Severity::from_u8(severity)?,
+    })
+}
+
+/// Port, device address, function code, first register as a little endian `u16`, register count,
+/// interval in milliseconds as a little endian `u32`, data type (0 u16, 1 i16, 2 u32, 3 f32), word
+/// order (0 high first, 1 low first) and scale.
+fn encode_poll(poll: &Poll) -> [u8; 13] {
+    let mut value = [0; 13];
+    value[0] = poll.port as u8;
+    value[1] = poll.address;
+    value[2] = poll.register_type.function();
+    value[3..5].copy_from_slice(&poll.start.to_le_bytes());
+    value[5] = poll.count;
+    let interval = poll.interval.as_millis().min(u32::MAX as u64) as u32;
+    value[6..10].copy_from_slice(&interval.to_le_bytes());
+    value[10] = match poll.data_type {
+        DataType::U16 => 0,
+        DataType::I16 => 1,
+        DataType::U32 => 2,
+        DataType::F32 => 3,
+    };
+    value[11] = match poll.word_order {
+        WordOrder::HighFirst => 0,
+        WordOrder::LowFirst => 1,
+    };
+    value[12] = poll.scale as u8;
+    value
+}
+
+fn decode_poll(value: &[u8]) -> Option<Poll> {
+    let [port, address, function, s0, s1, count, i0, i1, i2, i3, data_type, word_order, scale] =
+        *value
+    else {
+        return None;
+    };
+
+    let poll = Poll {
+        port: port as usize,
+        address,
+        register_type: match function {
+            0x03 => RegisterType::Holding,
+            0x04 => RegisterType::Input,
+            _ => return None,
+        },
+        start: u16::from_le_bytes([s0, s1]),
+        count,
+        interval: Duration::from_millis(u32::from_le_bytes([i0, i1, i2, i3]) as u64),
+        data_type: match data_type {
+            0 => DataType::U16,
+            1 => DataType::I16,
+            2 => DataType::U32,
+            3 => DataType::F32,
+            _ => return None,
+        },
+        word_order: match word_order {
+            0 => WordOrder::HighFirst,
+            1 => WordOrder::LowFirst,
+            _ => return None,
+        },
+        scale: scale as i8,
+    };
+
+    poll.is_valid().then_some(poll)
+}
+
+static CONFIG: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Config>> =
+    blocking_mutex::Mutex::new(Cell::new(Config::factory_default()));
+
+static STORAGE: OnceLock<Mutex<CriticalSectionRawMutex, Storage>> = OnceLock::new();
+
+/// Load the stored settings, falling back to factory defaults if there are none or they are
+/// unreadable. With `factory_reset` the stored settings are replaced by the factory defaults.
+pub(crate) async fn init(mut storage: Storage, factory_reset: bool) {
+    let mut record = [0; MAX_RECORD_SIZE];
+
+    let config = if factory_reset {
+        warn!("Restoring factory default settings");
+        None
+    } else {
+        match storage.read(&mut record) {
+            Some(len) => {
+                let config = Config::decode(&record[..len]);
+                if config.is_none() {
+                    warn!("Stored settings are invalid, using factory defaults");
+                }
+                config
+            }
+            None => {
+                info!("No stored settings, using factory defaults");
+                None
+            }
+        }
+    };
+
+    CONFIG.lock(|c| c.set(config.unwrap_or(Config::factory_default())));
+
+    let _ = STORAGE.init(Mutex::new(storage));
+
+    if factory_reset {
+        if let Err(e) = update(|config| *config = Config::factory_default()).await {
+            warn!("Failed to store factory default settings: {}", e);
+        }
+    }
+}
+
+/// The current settings.
+pub(crate) fn get() -> Config {
+    CONFIG.lock(Cell::get)
+}
+
+/// Change and store the settings, they take effect once each module next reads them.
+pub(crate) async fn update(f: impl FnOnce(&mut Config)) -> Result<(), storage::Error> {
+    let mut storage = STORAGE.get().await.lock().await;
+
+    let mut config = get();
+    f(&mut config);
+
+    storage.write(&config.encode())?;
+    CONFIG.lock(|c| c.set(config));
+    info!("Settings saved");
+    syslog::log(Severity::Notice, format_args!("Settings changed"));
+    Ok(())
+}
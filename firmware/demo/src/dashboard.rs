@@ -0,0 +1,113 @@
+//! The home screen: the network state, uptime and the traffic on each port at a glance.
+
+use crate::{
+    identity,
+    menu::{parity_letter, row, Page},
+    rs485::PORT_COUNT,
+    status::Status,
+};
+use core::fmt::Arguments;
+use embassy_time::Duration;
+use heapless::Vec;
+
+/// Shortest time over which byte rates are worked out, so they don't jump about when the page is
+/// built more often, such as on a button press.
+const RATE_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Copy)]
+struct Rates {
+    rx: u32,
+    tx: u32,
+}
+
+pub(crate) struct Dashboard {
+    /// The status the rates were last worked out from.
+    sample: Option<Status>,
+    /// Bytes per second on each port.
+    rates: [Rates; PORT_COUNT],
+}
+
+impl Dashboard {
+    pub(crate) const fn new() -> Self {
+        Self {
+            sample: None,
+            rates: [Rates { rx: 0, tx: 0 }; PORT_COUNT],
+        }
+    }
+
+    /// What to show for `status`, taking a new sample of the byte rates if it is due.
+    pub(crate) fn page(&mut self, status: &Status) -> Page {
+        self.update_rates(status);
+
+        let mut page = Page {
+            title: row(format_args!("{}", identity::hostname())),
+            rows: Vec::new(),
+            selected: None,
+        };
+        let mut add = |args: Arguments| {
+            let _ = page.rows.push(row(args));
+        };
+
+        match (status.link_up, &status.ipv4) {
+            (false, _) => add(format_args!("Link down")),
+            (true, None) => add(format_args!("Link up, no address")),
+            (true, Some(ipv4)) => add(format_args!("Link up, {}", ipv4.source.name())),
+        }
+        match &status.ipv4 {
+            Some(ipv4) => add(format_args!("{}", ipv4.address)),
+            None => add(format_args!("")),
+        }
+        let uptime = status.time.as_secs();
+        add(format_args!(
+            "Up {}d {:02}:{:02}:{:02}",
+            uptime / 86400,
+            uptime / 3600 % 24,
+            uptime / 60 % 60,
+            uptime % 60
+        ));
+
+        for (n, port) in status.ports.iter().enumerate() {
+            add(format_args!(""));
+            match port.line {
+                Some(line) => add(format_args!(
+                    "Port {n} {} {}{}{}",
+                    line.baudrate,
+                    line.data_bit_count(),
+                    parity_letter(line.parity),
+                    line.stop_bit_count()
+                )),
+                None => add(format_args!("Port {n} starting")),
+            }
+            let rates = self.rates[n];
+            add(format_args!("RX {} TX {} B/s", rates.rx, rates.tx));
+            add(format_args!("Errors {}", port.counts.errors()));
+        }
+
+        page
+    }
+
+    fn update_rates(&mut self, status: &Status) {
+        let Some(sample) = &self.sample else {
+            self.sample = Some(*status);
+            return;
+        };
+
+        let elapsed = status.time.saturating_duration_since(sample.time);
+        if elapsed < RATE_INTERVAL {
+            return;
+        }
+
+        let per_second = |before: u32, after: u32| {
+            let bytes = after.wrapping_sub(before) as u64;
+            (bytes * 1_000_000 / elapsed.as_micros()) as u32
+        };
+
+        for ((rates, before), after) in self.rates.iter_mut().zip(&sample.ports).zip(&status.ports)
+        {
+            rates.rx = per_second(before.counts.rx_bytes, after.counts.rx_bytes);
+            rates.tx = per_second(before.counts.tx_bytes, after.counts.tx_bytes);
+        }
+
+        self.sample = Some(*status);
+    }
+}
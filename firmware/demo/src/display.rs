@@ -1,23 +1,42 @@
-use crate::DisplayResources;
-use core::{cell::RefCell, convert::Infallible};
+use crate::{
+    backlight::{self, Backlight},
+    buttons::PRESSES,
+    dashboard::Dashboard,
+    menu::{self, Menu, Page, COLUMNS},
+    monitor::{self, Lines},
+    status, DisplayResources,
+};
+use core::{cell::RefCell, convert::Infallible, fmt::Write, ops::Range};
 use embassy_embedded_hal::shared_bus::blocking::spi::SpiDeviceWithConfig;
+use embassy_futures::select::{select, select4, Either4};
 use embassy_rp::{
     gpio::{Level, Output},
-    pwm::{Pwm, SetDutyCycle},
+    pwm::Pwm,
 };
 use embassy_sync::blocking_mutex::{raw::NoopRawMutex, Mutex};
-use embassy_time::{Delay, Timer};
+use embassy_time::{Delay, Duration, Timer};
 use embedded_graphics::{
-    mono_font::{ascii::FONT_10X20, MonoTextStyle},
+    mono_font::{
+        ascii::{FONT_10X20, FONT_6X10},
+        MonoFont, MonoTextStyle, MonoTextStyleBuilder,
+    },
     pixelcolor::Rgb565,
-    prelude::{DrawTarget, Primitive, WebColors},
+    prelude::{DrawTarget, Point, Primitive, WebColors},
     primitives::{Line, PrimitiveStyleBuilder, StrokeAlignment},
-    text::{Alignment, Text},
+    text::{Alignment, Baseline, Text},
     Drawable,
 };
 use embedded_hal::digital::{ErrorType, OutputPin};
+use heapless::String;
 use mipidsi::{interface::SpiInterface, models::ST7789, options::ColorInversion, Builder};
 
+/// How often the screen is updated to show changing values, such as counters.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1);
+/// Time taken for the backlight to light up with the boot screen.
+const BOOT_FADE_TIME: Duration = Duration::from_secs(2);
+/// Shortest time between updates of the traffic monitor.
+const MONITOR_INTERVAL: Duration = Duration::from_millis(200);
+
 #[embassy_executor::task]
 pub(super) async fn task(r: DisplayResources) {
     let mut config = embassy_rp::spi::Config::default();
@@ -31,11 +50,11 @@ pub(super) async fn task(r: DisplayResources) {
 
     let display_spi = SpiDeviceWithConfig::new(&spi_bus, NoCs, config);
 
-    let mut backlight = Pwm::new_output_a(
+    let mut backlight = Backlight::new(Pwm::new_output_a(
         r.backlight_pwm,
         r.backlight_pin,
         embassy_rp::pwm::Config::default(),
-    );
+    ));
 
     let dc = Output::new(r.dc_pin, Level::Low);
     let rst = Output::new(r.reset_pin, Level::Low);
@@ -52,30 +71,91 @@ pub(super) async fn task(r: DisplayResources) {
 
     BootScreen {}.draw(&mut display).unwrap();
 
-    let _ = backlight.set_duty_cycle_fraction(0, 8);
-    Timer::after_millis(250).await;
-    let _ = backlight.set_duty_cycle_fraction(1, 8);
-    Timer::after_millis(250).await;
-    let _ = backlight.set_duty_cycle_fraction(2, 8);
-    Timer::after_millis(250).await;
-    let _ = backlight.set_duty_cycle_fraction(3, 8);
-    Timer::after_millis(250).await;
-    let _ = backlight.set_duty_cycle_fraction(4, 8);
-    Timer::after_millis(250).await;
-    let _ = backlight.set_duty_cycle_fraction(5, 8);
-    Timer::after_millis(250).await;
-    let _ = backlight.set_duty_cycle_fraction(6, 8);
-    Timer::after_millis(250).await;
-    let _ = backlight.set_duty_cycle_fraction(7, 8);
-    Timer::after_millis(250).await;
-    let _ = backlight.set_duty_cycle_fraction(8, 8);
-    Timer::after_millis(250).await;
+    backlight.update(BOOT_FADE_TIME).await;
+
+    let mut menu = Menu::new();
+    let mut dashboard = Dashboard::new();
+    // What is on the display, so only the rows that change need to be drawn again
+    let mut shown = Shown::Nothing;
 
     loop {
-        Timer::after_secs(10).await;
+        let drawn = match menu.monitor() {
+            Some(view) => {
+                let lines = view.lines();
+                let shown = match &shown {
+                    Shown::Monitor(lines) => Some(lines),
+                    _ => None,
+                };
+                let update = MonitorUpdate {
+                    lines: &lines,
+                    shown,
+                };
+                update.draw(&mut display).map(|_| Shown::Monitor(lines))
+            }
+            None => {
+                let status = status::get();
+                let page = if menu.is_home() {
+                    dashboard.page(&status)
+                } else {
+                    menu.page(&status)
+                };
+                let shown = match &shown {
+                    Shown::Page(page) => Some(page),
+                    _ => None,
+                };
+                let update = PageUpdate { page: &page, shown };
+                update.draw(&mut display).map(|_| Shown::Page(page))
+            }
+        };
+        shown = drawn.unwrap_or(Shown::Nothing);
+
+        // Only after drawing, so the display lights up with what is current
+        backlight.update(backlight::FADE_TIME).await;
+
+        // Follow new traffic on the monitor, but no more often than it is worth drawing
+        let monitoring = menu.monitor().is_some_and(|view| !view.is_paused());
+        let traffic = async {
+            if monitoring {
+                monitor::recorded().await;
+                Timer::after(MONITOR_INTERVAL).await;
+            } else {
+                core::future::pending().await
+            }
+        };
+
+        match select4(
+            PRESSES.receive(),
+            select(status::changed(), traffic),
+            backlight::alarmed(),
+            Timer::after(REFRESH_INTERVAL),
+        )
+        .await
+        {
+            Either4::First(button) => {
+                // A press on a dark display only lights it, as nothing could be seen to select
+                let was_off = backlight.is_off();
+                backlight.touch();
+                if !was_off {
+                    if let Some(change) = menu.press(button) {
+                        menu::apply(change).await;
+                    }
+                }
+            }
+            Either4::Third(_) => backlight.touch(),
+            Either4::Second(_) | Either4::Fourth(_) => {}
+        }
     }
 }
 
+/// What was last drawn on the display.
+#[allow(clippy::large_enum_variant)]
+enum Shown {
+    /// Something that isn't kept, such as the boot screen.
+    Nothing,
+    Page(Page),
+    Monitor(Lines),
+}
+
 struct NoCs;
 
 impl OutputPin for NoCs {
@@ -140,3 +220,191 @@ impl Drawable for BootScreen {
         Ok(())
     }
 }
+
+/// A page drawn over the one on the display, sending only the parts of rows that differ.
+pub(crate) struct PageUpdate<'a> {
+    pub(crate) page: &'a Page,
+    /// The page on the display, `None` if something else is there.
+    pub(crate) shown: Option<&'a Page>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RowKind {
+    Title,
+    Item,
+    Selected,
+}
+
+/// The text of a row of the display padded to its full width, with the title as row 0.
+fn line(page: &Page, i: usize) -> (String<COLUMNS>, RowKind) {
+    let mut line = String::new();
+
+    let kind = if i == 0 {
+        let _ = write!(line, "{:^COLUMNS$}", page.title);
+        RowKind::Title
+    } else {
+        let text = page.rows.get(i - 1).map(|row| row.as_str()).unwrap_or("");
+        let _ = write!(line, "{text:<COLUMNS$}");
+        if page.selected == Some(i - 1) {
+            RowKind::Selected
+        } else {
+            RowKind::Item
+        }
+    };
+
+    (line, kind)
+}
+
+/// The characters of `line` that differ from `shown`, all of them if they can't be compared.
+fn changed_span(line: &str, shown: &str) -> Option<Range<usize>> {
+    if !line.is_ascii() || !shown.is_ascii() || line.len() != shown.len() {
+        return Some(0..line.len());
+    }
+
+    let (line, shown) = (line.as_bytes(), shown.as_bytes());
+    let start = (0..line.len()).find(|&i| line[i] != shown[i])?;
+    let end = (start..line.len()).rfind(|&i| line[i] != shown[i])? + 1;
+    Some(start..end)
+}
+
+impl Drawable for PageUpdate<'_> {
+    type Output = ();
+    type Color = Rgb565;
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        let title_style = title_style(&FONT_10X20);
+        let row_style = row_style(&FONT_10X20);
+        let selected_style = text_style(
+            &FONT_10X20,
+            Self::Color::CSS_WHITE,
+            Self::Color::CSS_ROYAL_BLUE,
+        );
+
+        for i in 0..menu::ROWS {
+            let (text, kind) = line(self.page, i);
+            let shown = self
+                .shown
+                .map(|shown| line(shown, i))
+                .filter(|(_, shown_kind)| *shown_kind == kind);
+
+            let style = match kind {
+                RowKind::Title => title_style,
+                RowKind::Item => row_style,
+                RowKind::Selected => selected_style,
+            };
+            draw_row(
+                target,
+                i,
+                &text,
+                shown.as_ref().map(|(text, _)| text.as_str()),
+                style,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The traffic monitor drawn over what it showed before, sending only the parts of lines that
+/// differ.
+pub(crate) struct MonitorUpdate<'a> {
+    pub(crate) lines: &'a Lines,
+    /// The monitor on the display, `None` if something else is there.
+    pub(crate) shown: Option<&'a Lines>,
+}
+
+/// A line of the monitor padded to its full width, with the title as line 0.
+fn monitor_line(lines: &Lines, i: usize) -> monitor::Line {
+    let mut line = monitor::Line::new();
+
+    if i == 0 {
+        let _ = write!(line, "{:^width$}", lines.title, width = monitor::COLUMNS);
+    } else {
+        let text = lines
+            .lines
+            .get(i - 1)
+            .map(|line| line.as_str())
+            .unwrap_or("");
+        let _ = write!(line, "{text:<width$}", width = monitor::COLUMNS);
+    }
+
+    line
+}
+
+impl Drawable for MonitorUpdate<'_> {
+    type Output = ();
+    type Color = Rgb565;
+
+    fn draw<D>(&self, target: &mut D) -> Result<Self::Output, D::Error>
+    where
+        D: DrawTarget<Color = Self::Color>,
+    {
+        for i in 0..monitor::ROWS {
+            let text = monitor_line(self.lines, i);
+            let shown = self.shown.map(|shown| monitor_line(shown, i));
+            let style = if i == 0 {
+                title_style(&FONT_6X10)
+            } else {
+                row_style(&FONT_6X10)
+            };
+            draw_row(target, i, &text, shown.as_deref(), style)?;
+        }
+
+        Ok(())
+    }
+}
+
+// Text is always drawn with a background so it replaces whatever was shown before without
+// clearing the display first
+fn text_style(
+    font: &'static MonoFont<'static>,
+    text_color: Rgb565,
+    background_color: Rgb565,
+) -> MonoTextStyle<'static, Rgb565> {
+    MonoTextStyleBuilder::new()
+        .font(font)
+        .text_color(text_color)
+        .background_color(background_color)
+        .build()
+}
+
+fn title_style(font: &'static MonoFont<'static>) -> MonoTextStyle<'static, Rgb565> {
+    text_style(font, Rgb565::CSS_WHITE, Rgb565::CSS_DARK_SLATE_GRAY)
+}
+
+fn row_style(font: &'static MonoFont<'static>) -> MonoTextStyle<'static, Rgb565> {
+    text_style(font, Rgb565::CSS_BLACK, Rgb565::CSS_WHITE)
+}
+
+/// Draw `text` as row `i` in the font of `style`, only the characters that differ from `shown` if
+/// that is what the row holds now.
+fn draw_row<D>(
+    target: &mut D,
+    i: usize,
+    text: &str,
+    shown: Option<&str>,
+    style: MonoTextStyle<'static, Rgb565>,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = Rgb565>,
+{
+    let span = match shown {
+        Some(shown) => match changed_span(text, shown) {
+            Some(span) => span,
+            None => return Ok(()),
+        },
+        None => 0..text.len(),
+    };
+
+    let char_size = style.font.character_size;
+    let position = Point::new(
+        span.start as i32 * char_size.width as i32,
+        i as i32 * char_size.height as i32,
+    );
+    Text::with_baseline(&text[span], position, style, Baseline::Top).draw(target)?;
+
+    Ok(())
+}
@@ -1,19 +1,81 @@
-use crate::{EthernetResources, SharedSpi, SharedSpiInner};
-use defmt::{info, unwrap};
+use crate::{
+    config::{self, Ipv4Mode},
+    http, identity, mdns, modbus_tcp, mqtt,
+    rs485::PORT_COUNT,
+    serial_server, sntp, stats, status,
+    syslog::{self, Severity},
+    EthernetResources, SharedSpi, SharedSpiInner,
+};
+use defmt::{info, unwrap, warn, Format};
 use embassy_embedded_hal::shared_bus::asynch::spi::SpiDeviceWithConfig;
 use embassy_executor::Spawner;
-use embassy_futures::yield_now;
-use embassy_net::{Stack, StackResources};
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    ConfigV4, DhcpConfig, IpEndpoint, Ipv4Address, Ipv4Cidr, Stack, StackResources, StaticConfigV4,
+};
 use embassy_net_wiznet::{chip::W5500, Device, Runner, State};
 use embassy_rp::{
     clocks::RoscRng,
     gpio::{Input, Level, Output, Pull},
     spi::Config,
 };
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_time::Timer;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, signal::Signal, watch::Watch};
+use embassy_time::{with_deadline, with_timeout, Duration, Instant};
 use static_cell::StaticCell;
 
+const DHCP_CLIENT_PORT: u16 = 68;
+const DHCP_SERVER_PORT: u16 = 67;
+/// How often a DHCP server is looked for while the fallback address is in use.
+const DHCP_PROBE_INTERVAL: Duration = Duration::from_secs(60);
+/// Largest DHCP message that hosts must accept, longer offers are ignored.
+const DHCP_PACKET_SIZE: usize = 576;
+/// BOOTP header, magic cookie, message type option and end option.
+const DHCP_DISCOVER_SIZE: usize = 244;
+const DHCP_MAGIC: [u8; 4] = [99, 130, 83, 99];
+const BOOTREQUEST: u8 = 1;
+const BOOTREPLY: u8 = 2;
+const HTYPE_ETHERNET: u8 = 1;
+const DHCP_OPTION_MESSAGE_TYPE: u8 = 53;
+const DHCPDISCOVER: u8 = 1;
+const DHCPOFFER: u8 = 2;
+
+/// Where the address in use came from.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum AddressSource {
+    Dhcp,
+    Static,
+    /// DHCP timed out and the static or link-local address was used instead.
+    Fallback,
+}
+
+impl AddressSource {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            AddressSource::Dhcp => "dhcp",
+            AddressSource::Static => "static",
+            AddressSource::Fallback => "fallback",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) struct Ipv4Status {
+    pub(crate) address: Ipv4Cidr,
+    pub(crate) source: AddressSource,
+}
+
+/// The IPv4 address in use, `None` while the link is down.
+pub(crate) static IPV4_STATUS: Watch<CriticalSectionRawMutex, Option<Ipv4Status>, 4> = Watch::new();
+
+/// Signalled when the IPv4 settings change, so the address is given up and acquired again.
+static IPV4_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Give up the address in use and get one as the IPv4 settings now say.
+pub(crate) fn ipv4_changed() {
+    IPV4_CHANGED.signal(());
+}
+
 #[embassy_executor::task]
 pub(super) async fn task(spawner: Spawner, spi: &'static SharedSpi, r: EthernetResources) {
     let mut rng = RoscRng;
@@ -29,7 +91,10 @@ pub(super) async fn task(spawner: Spawner, spi: &'static SharedSpi, r: EthernetR
     let w5500_int = Input::new(r.int_pin, Pull::Up);
     let w5500_reset = Output::new(r.rst_pin, Level::High);
 
-    let mac_addr = [0x02, 0x00, 0x00, 0x00, 0x00, 0x00];
+    let settings = config::get();
+
+    let mac_addr = settings.mac_address.unwrap_or_else(identity::mac_address);
+    info!("MAC address: {:02x}", mac_addr);
 
     static STATE: StaticCell<State<8, 8>> = StaticCell::new();
     let state = STATE.init(State::<8, 8>::new());
@@ -42,27 +107,269 @@ pub(super) async fn task(spawner: Spawner, spi: &'static SharedSpi, r: EthernetR
 
     let seed = rng.next_u64();
 
-    static RESOURCES: StaticCell<StackResources<3>> = StaticCell::new();
+    static RESOURCES: StaticCell<StackResources<22>> = StaticCell::new();
 
     let (stack, runner) = embassy_net::new(
         device,
-        embassy_net::Config::dhcpv4(Default::default()),
+        match settings.ipv4_mode {
+            Ipv4Mode::Static => {
+                embassy_net::Config::ipv4_static(static_config(&settings, mac_addr))
+            }
+            Ipv4Mode::Dhcp => embassy_net::Config::dhcpv4(dhcp_config()),
+        },
         RESOURCES.init(StackResources::new()),
         seed,
     );
 
     unwrap!(spawner.spawn(net_task(runner)));
+    unwrap!(spawner.spawn(syslog::task(stack)));
+
+    let mut servers_started = false;
+
+    loop {
+        stack.wait_link_up().await;
+        info!("Link up");
+        syslog::log(Severity::Notice, format_args!("Link up"));
+        status::set_link_up(true);
+
+        loop {
+            let settings = config::get();
+            let source = acquire_address(stack, &settings, mac_addr).await;
+
+            let cfg = wait_for_config(stack).await;
+            info!("IP address: {} ({})", cfg.address, source);
+            syslog::log(
+                Severity::Informational,
+                format_args!("IP address: {} ({})", cfg.address, source.name()),
+            );
+            let ipv4 = Ipv4Status {
+                address: cfg.address,
+                source,
+            };
+            IPV4_STATUS.sender().send(Some(ipv4));
+            status::set_ipv4(Some(ipv4));
+
+            if !servers_started {
+                start_servers(spawner, stack);
+                servers_started = true;
+            }
+
+            match select(keep_address(stack, source, mac_addr), IPV4_CHANGED.wait()).await {
+                Either::First(false) => break,
+                Either::First(true) => {}
+                Either::Second(_) => {
+                    info!("IPv4 settings changed, leaving the address");
+                    syslog::log(
+                        Severity::Notice,
+                        format_args!("IPv4 settings changed, leaving the address"),
+                    );
+                }
+            }
+            IPV4_STATUS.sender().send(None);
+            status::set_ipv4(None);
+        }
+
+        warn!("Link down");
+        syslog::log(Severity::Warning, format_args!("Link down"));
+        IPV4_STATUS.sender().send(None);
+        status::set_link_up(false);
+        status::set_ipv4(None);
+    }
+}
+
+/// Spawn the tasks serving clients, once the board first has an address.
+fn start_servers(spawner: Spawner, stack: Stack<'static>) {
+    for n in 0..modbus_tcp::TASKS {
+        unwrap!(spawner.spawn(modbus_tcp::task(stack, n % PORT_COUNT)));
+    }
+
+    for protocol in [
+        serial_server::Protocol::Raw,
+        serial_server::Protocol::Rfc2217,
+    ] {
+        for n in 0..serial_server::TASKS_PER_PROTOCOL {
+            unwrap!(spawner.spawn(serial_server::task(stack, n % PORT_COUNT, protocol)));
+        }
+    }
+
+    for _ in 0..http::MAX_CLIENTS {
+        unwrap!(spawner.spawn(http::task(stack)));
+    }
+
+    unwrap!(spawner.spawn(mdns::task(stack)));
+    unwrap!(spawner.spawn(mqtt::task(stack)));
+    unwrap!(spawner.spawn(sntp::task(stack)));
+}
 
-    info!("Waiting for DHCP...");
-    let cfg = wait_for_config(stack).await;
-    let local_addr = cfg.address.address();
-    info!("IP address: {:?}", local_addr);
+/// Get an address for the newly connected link, restarting DHCP in case the board has moved to
+/// another network.
+async fn acquire_address(
+    stack: Stack<'static>,
+    settings: &config::Config,
+    mac_addr: [u8; 6],
+) -> AddressSource {
+    match settings.ipv4_mode {
+        Ipv4Mode::Static => {
+            // The mode may have been changed from DHCP since the stack was started
+            stack.set_config_v4(ConfigV4::Static(static_config(settings, mac_addr)));
+            AddressSource::Static
+        }
+        Ipv4Mode::Dhcp => {
+            stack.set_config_v4(ConfigV4::Dhcp(dhcp_config()));
+
+            info!("Waiting for DHCP...");
+            match with_timeout(settings.dhcp_timeout, wait_for_config(stack)).await {
+                Ok(_) => {
+                    stats::record_dhcp_lease();
+                    AddressSource::Dhcp
+                }
+                Err(_) => {
+                    warn!("No DHCP lease, using the static address");
+                    syslog::log(
+                        Severity::Warning,
+                        format_args!("No DHCP lease, using the static address"),
+                    );
+                    let config = static_config(settings, mac_addr);
+                    stack.set_config_v4(ConfigV4::Static(config));
+                    AddressSource::Fallback
+                }
+            }
+        }
+    }
+}
+
+/// Wait until the address from `source` has to be given up, returns false if that is because the
+/// link went down, or true if a new one should be acquired.
+async fn keep_address(stack: Stack<'static>, source: AddressSource, mac_addr: [u8; 6]) -> bool {
+    match source {
+        AddressSource::Static => {
+            stack.wait_link_down().await;
+            false
+        }
+        AddressSource::Dhcp => {
+            // The stack drops the lease when the link goes down, or when the server refuses to
+            // renew it, in which case a new one is needed
+            stack.wait_config_down().await;
+            if !stack.is_link_up() {
+                return false;
+            }
+            warn!("DHCP lease lost");
+            syslog::log(Severity::Warning, format_args!("DHCP lease lost"));
+            true
+        }
+        AddressSource::Fallback => {
+            // Go back to DHCP as soon as a server turns up
+            match select(
+                stack.wait_link_down(),
+                wait_for_dhcp_server(stack, mac_addr),
+            )
+            .await
+            {
+                Either::First(_) => false,
+                Either::Second(_) => {
+                    info!("DHCP server found, leaving the fallback address");
+                    syslog::log(
+                        Severity::Notice,
+                        format_args!("DHCP server found, leaving the fallback address"),
+                    );
+                    true
+                }
+            }
+        }
+    }
+}
+
+/// Wait until a DHCP server answers a DHCPDISCOVER, sent again every [`DHCP_PROBE_INTERVAL`].
+///
+/// Offers are only looked at, never taken up, as the fallback address stays in use until the stack
+/// is switched back to DHCP.
+async fn wait_for_dhcp_server(stack: Stack<'static>, mac_addr: [u8; 6]) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 2 * DHCP_PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; DHCP_PACKET_SIZE];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    if let Err(e) = socket.bind(DHCP_CLIENT_PORT) {
+        warn!("Failed to bind DHCP probe socket: {}", e);
+        return core::future::pending().await;
+    }
+
+    let server = IpEndpoint::new(Ipv4Address::BROADCAST.into(), DHCP_SERVER_PORT);
+
+    loop {
+        let xid = RoscRng.next_u64() as u32;
+
+        let mut discover = [0; DHCP_DISCOVER_SIZE];
+        discover[0] = BOOTREQUEST;
+        discover[1] = HTYPE_ETHERNET;
+        discover[2] = mac_addr.len() as u8;
+        discover[4..8].copy_from_slice(&xid.to_be_bytes());
+        // Ask for a broadcast reply, a unicast one would go to the offered address
+        discover[10] = 0x80;
+        discover[28..34].copy_from_slice(&mac_addr);
+        discover[236..240].copy_from_slice(&DHCP_MAGIC);
+        discover[240..].copy_from_slice(&[DHCP_OPTION_MESSAGE_TYPE, 1, DHCPDISCOVER, 0xff]);
+
+        if let Err(e) = socket.send_to(&discover, server).await {
+            warn!("Failed to send DHCP probe: {}", e);
+        }
+
+        let deadline = Instant::now() + DHCP_PROBE_INTERVAL;
+        let mut offer = [0; DHCP_PACKET_SIZE];
+        while let Ok(res) = with_deadline(deadline, socket.recv_from(&mut offer)).await {
+            if let Ok((len, _)) = res {
+                if is_offer(&offer[..len], xid) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Whether `packet` is a DHCPOFFER in reply to the DHCPDISCOVER with transaction ID `xid`.
+fn is_offer(packet: &[u8], xid: u32) -> bool {
+    if packet.len() < 240
+        || packet[0] != BOOTREPLY
+        || packet[4..8] != xid.to_be_bytes()
+        || packet[236..240] != DHCP_MAGIC
+    {
+        return false;
+    }
 
+    let mut options = &packet[240..];
     loop {
-        Timer::after_secs(10).await;
+        match options {
+            [0, rest @ ..] => options = rest,
+            [code, len, rest @ ..] if *code != 0xff => {
+                let Some((value, rest)) = rest.split_at_checked(*len as usize) else {
+                    return false;
+                };
+                if *code == DHCP_OPTION_MESSAGE_TYPE {
+                    return value == [DHCPOFFER];
+                }
+                options = rest;
+            }
+            _ => return false,
+        }
     }
 }
 
+/// DHCP settings, with the hostname sent so the board can be found by name on networks that
+/// register DHCP clients in DNS.
+fn dhcp_config() -> DhcpConfig {
+    let mut config = DhcpConfig::default();
+    config.hostname = identity::hostname().as_str().try_into().ok();
+    config
+}
+
 #[embassy_executor::task]
 async fn ethernet_task(
     runner: Runner<
@@ -81,11 +388,38 @@ async fn net_task(mut runner: embassy_net::Runner<'static, Device<'static>>) ->
     runner.run().await
 }
 
-async fn wait_for_config(stack: Stack<'static>) -> embassy_net::StaticConfigV4 {
+async fn wait_for_config(stack: Stack<'static>) -> StaticConfigV4 {
     loop {
+        stack.wait_config_up().await;
         if let Some(config) = stack.config_v4() {
-            return config.clone();
+            return config;
+        }
+    }
+}
+
+/// The configured static address, or a link-local one picked from the MAC address if there is
+/// none.
+fn static_config(settings: &config::Config, mac_addr: [u8; 6]) -> StaticConfigV4 {
+    match settings.static_ipv4 {
+        Some(static_ipv4) => {
+            let mut config = StaticConfigV4 {
+                address: static_ipv4.address,
+                gateway: static_ipv4.gateway,
+                dns_servers: Default::default(),
+            };
+            if let Some(dns_server) = static_ipv4.dns_server {
+                let _ = config.dns_servers.push(dns_server);
+            }
+            config
+        }
+        None => {
+            // RFC 3927 excludes the first and last 256 addresses of the range
+            let address = Ipv4Address::new(169, 254, 1 + mac_addr[4] % 254, mac_addr[5]);
+            StaticConfigV4 {
+                address: Ipv4Cidr::new(address, 16),
+                gateway: None,
+                dns_servers: Default::default(),
+            }
         }
-        yield_now().await;
     }
 }
@@ -0,0 +1,130 @@
+//! Grouping of received bytes into frames separated by idle time on the line.
+//!
+//! The UART only reports received data once its FIFO is half full or the line has been idle for
+//! around three characters, so gaps shorter than that cannot be detected.
+
+use defmt::Format;
+use embassy_rp::uart::{BufferedUartRx, Error};
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::Read;
+
+/// Idle time on the line that ends a frame.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum IdleGap {
+    /// Tenths of a character time, e.g. 35 for the Modbus RTU t3.5 gap.
+    CharTimes(u16),
+    /// Fixed time in microseconds.
+    Micros(u32),
+}
+
+impl IdleGap {
+    /// The gap as named in the HTTP API, with its value in that unit.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            IdleGap::CharTimes(_) => "char_times",
+            IdleGap::Micros(_) => "micros",
+        }
+    }
+
+    pub(crate) fn value(&self) -> u32 {
+        match self {
+            IdleGap::CharTimes(tenths) => *tenths as u32,
+            IdleGap::Micros(micros) => *micros,
+        }
+    }
+
+    /// The gap with the given [`name`](Self::name) and value, `None` if either is invalid.
+    pub(crate) fn from_name(name: &str, value: u32) -> Option<Self> {
+        match name {
+            "char_times" => u16::try_from(value).ok().map(IdleGap::CharTimes),
+            "micros" => Some(IdleGap::Micros(value)),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn duration(&self, char_time: Duration) -> Duration {
+        match self {
+            IdleGap::CharTimes(tenths) => char_time * *tenths as u32 / 10,
+            IdleGap::Micros(micros) => Duration::from_micros(*micros as u64),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) struct Framing {
+    pub(crate) idle_gap: IdleGap,
+    /// A frame is ended early once it reaches this length.
+    pub(crate) max_len: usize,
+}
+
+/// Encoded framing as stored in the settings.
+///
+/// The idle gap kind (0 for no framing, 1 for tenths of a character time, 2 for microseconds), the
+/// idle gap as a little endian `u32` and the maximum frame length as a little endian `u16`.
+pub(crate) type FramingBytes = [u8; 7];
+
+pub(crate) fn to_bytes(framing: Option<Framing>) -> FramingBytes {
+    let (kind, gap, max_len) = match framing {
+        None => (0, 0, 0),
+        Some(Framing {
+            idle_gap: IdleGap::CharTimes(tenths),
+            max_len,
+        }) => (1, tenths as u32, max_len),
+        Some(Framing {
+            idle_gap: IdleGap::Micros(micros),
+            max_len,
+        }) => (2, micros, max_len),
+    };
+
+    let mut bytes = [0; 7];
+    bytes[0] = kind;
+    bytes[1..5].copy_from_slice(&gap.to_le_bytes());
+    bytes[5..7].copy_from_slice(&(max_len.min(u16::MAX as usize) as u16).to_le_bytes());
+    bytes
+}
+
+/// Returns `None` if the idle gap kind is unknown.
+pub(crate) fn from_bytes(bytes: FramingBytes) -> Option<Option<Framing>> {
+    let gap = u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+    let max_len = u16::from_le_bytes([bytes[5], bytes[6]]) as usize;
+
+    let idle_gap = match bytes[0] {
+        0 => return Some(None),
+        1 => IdleGap::CharTimes(gap.min(u16::MAX as u32) as u16),
+        2 => IdleGap::Micros(gap),
+        _ => return None,
+    };
+
+    Some(Some(Framing { idle_gap, max_len }))
+}
+
+impl Framing {
+    /// Longest frame that fits in a buffer of `buf_len` bytes.
+    pub(crate) fn frame_len(&self, buf_len: usize) -> usize {
+        self.max_len.clamp(1, buf_len)
+    }
+}
+
+/// Read the rest of a frame whose first `len` bytes are in `buf`, until the line has been idle for
+/// the framing gap or the frame is full.
+///
+/// Frames containing receive errors are discarded and the error returned.
+pub(crate) async fn finish_frame(
+    rx: &mut BufferedUartRx,
+    buf: &mut [u8],
+    mut len: usize,
+    framing: &Framing,
+    char_time: Duration,
+) -> Result<usize, Error> {
+    let max_len = framing.frame_len(buf.len());
+    let idle_gap = framing.idle_gap.duration(char_time);
+
+    while len < max_len {
+        match with_timeout(idle_gap, rx.read(&mut buf[len..max_len])).await {
+            Ok(n) => len += n?,
+            Err(_) => break,
+        }
+    }
+
+    Ok(len)
+}
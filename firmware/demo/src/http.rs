@@ -0,0 +1,1062 @@
+//! HTTP/1.1 server with a status page and a JSON API for reading and changing settings.
+//!
+//! - `GET /`: status page
+//! - `GET /api/status`: network state, uptime, UTC time if known and the state of each port
+//! - `GET /api/ports/<n>`: line settings and counters of a port
+//! - `PUT /api/ports/<n>`: change and store the line settings of a port, the body is an object
+//!   with any of the settings returned by `GET`. Received data is grouped into frames ended by
+//!   `idle_gap` tenths of a character time or microseconds of quiet, as `framing` is
+//!   `char_times` or `micros`, or passed on as it arrives if it is `none`.
+//! - `GET /api/settings`: board settings, such as how a second client of a port is handled
+//! - `PUT /api/settings`: change and store board settings, the body is an object with any of the
+//!   settings returned by `GET`. The MAC address and IPv4 settings take effect after a restart,
+//!   the syslog and NTP servers straight away. The syslog minimum severity may be given as a
+//!   name or as its RFC 5424 number.
+//! - `GET /api/mqtt`: address and port of the MQTT broker, the address is `null` if there is none
+//! - `PUT /api/mqtt`: change and store the MQTT broker, the client connects to it straight away.
+//!   The port is 1883 if not given, a `null` address stops the client.
+//! - `GET /api/polls`: the Modbus poll list, with `null` for each unused entry
+//! - `PUT /api/polls/<i>`: change and store entry `i` of the poll list, the body is an object
+//!   with any of the settings returned by `GET`. A new poll needs at least `address`, `count` and
+//!   `interval_ms`.
+//! - `DELETE /api/polls/<i>`: clear entry `i` of the poll list, as does a `PUT` of `null`
+//! - `POST /api/reboot`: restart the board once the response is sent
+//! - `GET /metrics`: counters in the Prometheus text format
+//! - `GET /terminal`: terminal on the RS485 ports using [`websocket`]
+//! - `GET /ws/port/<n>`: WebSocket connected to a port
+//!
+//! Each connection carries a single request, or becomes a WebSocket.
+
+use crate::{
+    clock,
+    config::{self, Config, Ipv4Mode, MqttBroker, StaticIpv4},
+    ethernet::IPV4_STATUS,
+    framing::{Framing, IdleGap},
+    identity,
+    json::{self, Value},
+    mqtt,
+    poller::{DataType, Poll, RegisterType, WordOrder, MAX_POLLS},
+    rs485::{PAYLOAD_SIZE, PORTS, PORT_COUNT},
+    serial_server::ClientPolicy,
+    stats,
+    syslog::{self, Severity, SyslogServer},
+    websocket,
+};
+use core::fmt::Write as _;
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_net::{tcp::TcpSocket, Ipv4Address, Ipv4Cidr, Stack};
+use embassy_rp::uart::{DataBits, Parity, StopBits};
+use embassy_time::{Duration, Instant, Timer};
+use embedded_io_async::Write;
+use heapless::String;
+use portable_atomic::Ordering;
+
+pub(crate) const TCP_PORT: u16 = 80;
+
+/// Number of HTTP clients that can be connected at once, including WebSockets.
+pub(crate) const MAX_CLIENTS: usize = 3;
+
+/// Largest request accepted, headers and body together.
+const MAX_REQUEST_SIZE: usize = 1024;
+const MAX_RESPONSE_SIZE: usize = 2048;
+const MAX_METRICS_SIZE: usize = 4096;
+
+type Body = String<MAX_RESPONSE_SIZE>;
+
+const TERMINAL_PAGE: &str = include_str!("terminal.html");
+
+#[embassy_executor::task(pool_size = MAX_CLIENTS)]
+pub(super) async fn task(stack: Stack<'static>) {
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(10)));
+
+        if let Err(e) = socket.accept(TCP_PORT).await {
+            warn!("HTTP accept failed: {}", e);
+            continue;
+        }
+        stats::record_accept();
+
+        let reboot = match select(serve(&mut socket, stack), stack.wait_link_down()).await {
+            Either::First(Ok(reboot)) => reboot,
+            _ => false,
+        };
+
+        // Let the response reach the client before the connection goes away
+        socket.close();
+        let _ = socket.flush().await;
+
+        if reboot {
+            warn!("Rebooting at the request of an HTTP client");
+            Timer::after_millis(100).await;
+            stats::reset();
+        }
+    }
+}
+
+struct Disconnected {}
+
+impl From<embassy_net::tcp::Error> for Disconnected {
+    fn from(_: embassy_net::tcp::Error) -> Self {
+        Disconnected {}
+    }
+}
+
+enum Error {
+    Disconnected,
+    BadRequest,
+    TooLarge,
+}
+
+impl From<embassy_net::tcp::Error> for Error {
+    fn from(_: embassy_net::tcp::Error) -> Self {
+        Error::Disconnected
+    }
+}
+
+struct Request<'a> {
+    method: &'a str,
+    path: &'a str,
+    query: &'a str,
+    /// Header fields, one per line.
+    fields: &'a str,
+    body: &'a str,
+}
+
+impl Request<'_> {
+    fn field(&self, name: &str) -> Option<&str> {
+        self.fields.split("\r\n").find_map(|line| {
+            let (field, value) = line.split_once(':')?;
+            field.eq_ignore_ascii_case(name).then_some(value.trim())
+        })
+    }
+}
+
+struct Response {
+    status: &'static str,
+    content_type: &'static str,
+    body: Body,
+    /// Restart the board once the response is sent.
+    reboot: bool,
+}
+
+impl Response {
+    fn new(content_type: &'static str) -> Self {
+        Self {
+            status: "200 OK",
+            content_type,
+            body: Body::new(),
+            reboot: false,
+        }
+    }
+
+    fn error(status: &'static str) -> Self {
+        let mut response = Self::new("text/plain");
+        response.status = status;
+        let _ = response.body.push_str(status);
+        response
+    }
+}
+
+/// Serve a request, returns true if the board should be restarted.
+async fn serve(socket: &mut TcpSocket<'_>, stack: Stack<'_>) -> Result<bool, Disconnected> {
+    let mut buf = [0; MAX_REQUEST_SIZE];
+
+    let response = match read_request(socket, &mut buf).await {
+        Ok(request) => {
+            info!("HTTP {} {}", request.method, request.path);
+
+            match (request.method, request.path) {
+                ("GET", "/terminal") => {
+                    send(socket, "200 OK", "text/html", TERMINAL_PAGE.as_bytes()).await?;
+                    return Ok(false);
+                }
+                ("GET", "/metrics") => {
+                    let body = metrics(stack);
+                    let content_type = "text/plain; version=0.0.4";
+                    send(socket, "200 OK", content_type, body.as_bytes()).await?;
+                    return Ok(false);
+                }
+                ("GET", path) if path.starts_with("/ws/port/") => {
+                    let n = path["/ws/port/".len()..]
+                        .parse::<usize>()
+                        .ok()
+                        .filter(|n| *n < PORT_COUNT);
+                    let upgrade = request
+                        .field("upgrade")
+                        .is_some_and(|u| u.eq_ignore_ascii_case("websocket"));
+
+                    match (n, request.field("sec-websocket-key")) {
+                        (Some(n), Some(key)) if upgrade => {
+                            let options = websocket::Options::from_query(request.query);
+                            websocket::serve(socket, n, key, options).await;
+                            return Ok(false);
+                        }
+                        (None, _) => Response::error("404 Not Found"),
+                        _ => Response::error("400 Bad Request"),
+                    }
+                }
+                _ => handle(&request, stack).await,
+            }
+        }
+        Err(Error::Disconnected) => return Err(Disconnected {}),
+        Err(Error::BadRequest) => Response::error("400 Bad Request"),
+        Err(Error::TooLarge) => Response::error("413 Content Too Large"),
+    };
+
+    send(
+        socket,
+        response.status,
+        response.content_type,
+        response.body.as_bytes(),
+    )
+    .await?;
+
+    Ok(response.reboot)
+}
+
+async fn send(
+    socket: &mut TcpSocket<'_>,
+    status: &str,
+    content_type: &str,
+    body: &[u8],
+) -> Result<(), Disconnected> {
+    let mut header = String::<128>::new();
+    let _ = write!(
+        header,
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+
+    socket.write_all(header.as_bytes()).await?;
+    socket.write_all(body).await?;
+    socket.flush().await?;
+    Ok(())
+}
+
+async fn read_request<'a>(
+    socket: &mut TcpSocket<'_>,
+    buf: &'a mut [u8],
+) -> Result<Request<'a>, Error> {
+    let mut len = 0;
+
+    let header_len = loop {
+        if let Some(end) = buf[..len].windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if len == buf.len() {
+            return Err(Error::TooLarge);
+        }
+
+        let n = socket.read(&mut buf[len..]).await?;
+        if n == 0 {
+            return Err(Error::Disconnected);
+        }
+        len += n;
+    };
+
+    let (_, content_length) = parse_header(&buf[..header_len]).ok_or(Error::BadRequest)?;
+
+    let end = header_len
+        .checked_add(content_length)
+        .filter(|end| *end <= buf.len())
+        .ok_or(Error::TooLarge)?;
+
+    while len < end {
+        let n = socket.read(&mut buf[len..end]).await?;
+        if n == 0 {
+            return Err(Error::Disconnected);
+        }
+        len += n;
+    }
+
+    let (header, body) = buf[..end].split_at(header_len);
+    let (mut request, _) = parse_header(header).ok_or(Error::BadRequest)?;
+    request.body = core::str::from_utf8(body).map_err(|_| Error::BadRequest)?;
+
+    Ok(request)
+}
+
+/// Returns the request without its body, and the length of the body.
+fn parse_header(header: &[u8]) -> Option<(Request<'_>, usize)> {
+    let header = core::str::from_utf8(header).ok()?;
+    let (request_line, fields) = header.split_once("\r\n")?;
+
+    let mut request_line = request_line.split(' ');
+    let method = request_line.next()?;
+    let target = request_line.next()?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let request = Request {
+        method,
+        path,
+        query,
+        fields,
+        body: "",
+    };
+
+    let content_length = match request.field("content-length") {
+        Some(len) => len.parse().ok()?,
+        None => 0,
+    };
+
+    Some((request, content_length))
+}
+
+async fn handle(request: &Request<'_>, stack: Stack<'_>) -> Response {
+    let port = request
+        .path
+        .strip_prefix("/api/ports/")
+        .map(|n| n.parse::<usize>().ok().filter(|n| *n < PORT_COUNT));
+
+    match (request.method, request.path, port) {
+        ("GET", "/", _) => status_page(stack),
+        ("GET", "/api/status", _) => status(stack),
+        ("GET", "/api/settings", _) => {
+            let mut response = Response::new("application/json");
+            write_settings(&mut response.body, &config::get());
+            response
+        }
+        ("PUT", "/api/settings", _) => update_settings(request.body).await,
+        ("GET", "/api/mqtt", _) => {
+            let mut response = Response::new("application/json");
+            write_mqtt(&mut response.body, config::get().mqtt_broker);
+            response
+        }
+        ("PUT", "/api/mqtt", _) => update_mqtt(request.body).await,
+        ("GET", "/api/polls", _) => {
+            let mut response = Response::new("application/json");
+            write_polls(&mut response.body, &config::get().polls);
+            response
+        }
+        (_, path, _) if path.starts_with("/api/polls/") => handle_poll(request).await,
+        ("POST", "/api/reboot", _) => {
+            let mut response = Response::new("application/json");
+            let _ = response.body.push_str("{}");
+            response.reboot = true;
+            response
+        }
+        (_, _, Some(None)) => Response::error("404 Not Found"),
+        ("GET", _, Some(Some(n))) => {
+            let mut response = Response::new("application/json");
+            write_port(&mut response.body, n);
+            response
+        }
+        ("PUT", _, Some(Some(n))) => update_port(n, request.body).await,
+        (
+            _,
+            "/" | "/api/status" | "/api/settings" | "/api/mqtt" | "/api/polls" | "/api/reboot",
+            _,
+        )
+        | (_, _, Some(_)) => Response::error("405 Method Not Allowed"),
+        _ => Response::error("404 Not Found"),
+    }
+}
+
+fn status(stack: Stack<'_>) -> Response {
+    let mut response = Response::new("application/json");
+    let body = &mut response.body;
+
+    let _ = write!(
+        body,
+        "{{\"hostname\":\"{}\",\"mac_address\":\"{}\",\"link_up\":{},\"ipv4\":",
+        identity::hostname(),
+        stack.hardware_address(),
+        stack.is_link_up()
+    );
+    match IPV4_STATUS.try_get().flatten() {
+        Some(status) => {
+            let _ = write!(
+                body,
+                "{{\"address\":\"{}\",\"source\":\"{}\"}}",
+                status.address,
+                status.source.name()
+            );
+        }
+        None => {
+            let _ = body.push_str("null");
+        }
+    }
+    let _ = write!(body, ",\"uptime\":{},\"time\":", Instant::now().as_secs());
+    let _ = match clock::now() {
+        Some(time) => write!(body, "\"{time}\""),
+        None => body.push_str("null").map_err(|_| core::fmt::Error),
+    };
+    let _ = body.push_str(",\"ports\":[");
+    for n in 0..PORT_COUNT {
+        if n > 0 {
+            let _ = body.push(',');
+        }
+        write_port(body, n);
+    }
+    let _ = body.push_str("]}");
+
+    response
+}
+
+fn write_port(body: &mut Body, n: usize) {
+    let line = PORTS[n].config();
+    let framing = PORTS[n].framing();
+    let counts = stats::PORTS[n].get();
+
+    let _ = write!(
+        body,
+        "{{\"baudrate\":{},\"data_bits\":{},\"parity\":\"{}\",\"stop_bits\":{},\"framing\":\"{}\",\
+         \"idle_gap\":",
+        line.baudrate,
+        line.data_bit_count(),
+        parity_name(line.parity),
+        line.stop_bit_count(),
+        framing.map_or("none", |f| f.idle_gap.name()),
+    );
+    write_number_or_null(body, framing.map(|f| f.idle_gap.value()));
+    let _ = body.push_str(",\"max_frame_len\":");
+    write_number_or_null(body, framing.map(|f| f.max_len));
+    let _ = write!(
+        body,
+        ",\"rx_bytes\":{},\"tx_bytes\":{},\"errors\":{}}}",
+        counts.rx_bytes,
+        counts.tx_bytes,
+        counts.errors()
+    );
+}
+
+fn write_number_or_null(body: &mut Body, value: Option<impl core::fmt::Display>) {
+    let _ = match value {
+        Some(value) => write!(body, "{value}"),
+        None => write!(body, "null"),
+    };
+}
+
+async fn update_port(n: usize, body: &str) -> Response {
+    let mut line = PORTS[n].config();
+    // The framing parts are put together once all of them are known, as they may come in any
+    // order
+    let framing = PORTS[n].framing();
+    let mut framing_name = framing.map(|f| f.idle_gap.name());
+    let mut idle_gap = framing.map(|f| f.idle_gap.value());
+    let mut max_frame_len = framing.map_or(PAYLOAD_SIZE, |f| f.max_len);
+
+    let parsed = json::for_each_member(body, |key, value| {
+        match (key, value) {
+            ("baudrate", Value::Number(baudrate)) => {
+                line.baudrate = u32::try_from(baudrate).ok().filter(|b| *b > 0)?;
+            }
+            ("data_bits", Value::Number(bits)) => {
+                line.data_bits = match bits {
+                    5 => DataBits::DataBits5,
+                    6 => DataBits::DataBits6,
+                    7 => DataBits::DataBits7,
+                    8 => DataBits::DataBits8,
+                    _ => return None,
+                };
+            }
+            ("parity", Value::String(parity)) => {
+                line.parity = match parity {
+                    "none" => Parity::ParityNone,
+                    "odd" => Parity::ParityOdd,
+                    "even" => Parity::ParityEven,
+                    _ => return None,
+                };
+            }
+            ("stop_bits", Value::Number(bits)) => {
+                line.stop_bits = match bits {
+                    1 => StopBits::STOP1,
+                    2 => StopBits::STOP2,
+                    _ => return None,
+                };
+            }
+            ("framing", Value::String("none")) => framing_name = None,
+            ("framing", Value::String(name)) => framing_name = Some(name),
+            ("idle_gap", Value::Number(gap)) => idle_gap = Some(u32::try_from(gap).ok()?),
+            ("max_frame_len", Value::Number(len)) => {
+                max_frame_len = usize::try_from(len)
+                    .ok()
+                    .filter(|len| (1..=PAYLOAD_SIZE).contains(len))?;
+            }
+            // Left out of what `GET` returns while there is no framing
+            ("idle_gap" | "max_frame_len", Value::Null) => {}
+            // Counters are ignored so that what `GET` returns can be sent back with changes
+            ("rx_bytes" | "tx_bytes" | "errors", _) => {}
+            _ => return None,
+        }
+        Some(())
+    });
+
+    let framing = match framing_name {
+        None => Some(None),
+        Some(name) => idle_gap
+            .and_then(|gap| IdleGap::from_name(name, gap))
+            .filter(|gap| gap.value() > 0)
+            .map(|idle_gap| {
+                Some(Framing {
+                    idle_gap,
+                    max_len: max_frame_len,
+                })
+            }),
+    };
+
+    let (Some(()), Some(framing)) = (parsed, framing) else {
+        return Response::error("400 Bad Request");
+    };
+
+    PORTS[n].set_config(line);
+    PORTS[n].set_framing(framing);
+
+    if let Err(e) = config::update(|config| {
+        config.ports[n] = line;
+        config.framings[n] = framing;
+    })
+    .await
+    {
+        warn!("Failed to save port {} settings: {}", n, e);
+        return Response::error("500 Internal Server Error");
+    }
+
+    info!("Port {} settings changed over HTTP", n);
+
+    let mut response = Response::new("application/json");
+    write_port(&mut response.body, n);
+    response
+}
+
+fn write_settings(body: &mut Body, config: &Config) {
+    let _ = write!(
+        body,
+        "{{\"client_policy\":\"{}\",\"mac_address\":",
+        config.client_policy.name()
+    );
+    match config.mac_address {
+        Some(mac) => {
+            let _ = body.push('"');
+            for (i, byte) in mac.iter().enumerate() {
+                let separator = if i > 0 { ":" } else { "" };
+                let _ = write!(body, "{separator}{byte:02x}");
+            }
+            let _ = body.push('"');
+        }
+        None => {
+            let _ = body.push_str("null");
+        }
+    }
+
+    let static_ipv4 = config.static_ipv4;
+    let _ = write!(
+        body,
+        ",\"ipv4_mode\":\"{}\",\"dhcp_timeout\":{},\"static_address\":",
+        config.ipv4_mode.name(),
+        config.dhcp_timeout.as_secs()
+    );
+    write_string_or_null(body, static_ipv4.map(|s| s.address));
+    let _ = body.push_str(",\"gateway\":");
+    write_string_or_null(body, static_ipv4.and_then(|s| s.gateway));
+    let _ = body.push_str(",\"dns_server\":");
+    write_string_or_null(body, static_ipv4.and_then(|s| s.dns_server));
+
+    let syslog = config.syslog;
+    let _ = body.push_str(",\"syslog_server\":");
+    write_string_or_null(body, syslog.map(|s| s.address));
+    let _ = write!(
+        body,
+        ",\"syslog_port\":{},\"syslog_min_severity\":\"{}\",\"ntp_server\":",
+        syslog.map_or(syslog::DEFAULT_PORT, |s| s.port),
+        syslog
+            .map_or(syslog::DEFAULT_MIN_SEVERITY, |s| s.min_severity)
+            .name()
+    );
+    write_string_or_null(body, config.ntp_server);
+    let _ = body.push('}');
+}
+
+fn write_string_or_null(body: &mut Body, value: Option<impl core::fmt::Display>) {
+    let _ = match value {
+        Some(value) => write!(body, "\"{value}\""),
+        None => write!(body, "null"),
+    };
+}
+
+/// Change `config` as set out in a `PUT /api/settings` body, returns `None` if it is invalid.
+fn apply_settings(config: &mut Config, body: &str) -> Option<()> {
+    // The static address parts are put together once all of them are known, as they may come in
+    // any order
+    let mut address = config.static_ipv4.map(|s| s.address);
+    let mut gateway = config.static_ipv4.and_then(|s| s.gateway);
+    let mut dns_server = config.static_ipv4.and_then(|s| s.dns_server);
+    // Likewise the syslog server, whose port and severity are kept while there is no address
+    let mut syslog_address = config.syslog.map(|s| s.address);
+    let mut syslog_port = config.syslog.map_or(syslog::DEFAULT_PORT, |s| s.port);
+    let mut min_severity = config
+        .syslog
+        .map_or(syslog::DEFAULT_MIN_SEVERITY, |s| s.min_severity);
+
+    json::for_each_member(body, |key, value| {
+        match (key, value) {
+            ("client_policy", Value::String(name)) => {
+                config.client_policy = ClientPolicy::ALL.into_iter().find(|p| p.name() == name)?;
+            }
+            ("mac_address", Value::String(mac)) => config.mac_address = Some(parse_mac(mac)?),
+            ("mac_address", Value::Null) => config.mac_address = None,
+            ("ipv4_mode", Value::String(name)) => {
+                config.ipv4_mode = Ipv4Mode::ALL.into_iter().find(|m| m.name() == name)?;
+            }
+            ("dhcp_timeout", Value::Number(secs)) => {
+                let secs = u16::try_from(secs).ok().filter(|secs| *secs > 0)?;
+                config.dhcp_timeout = Duration::from_secs(secs as u64);
+            }
+            ("static_address", Value::String(cidr)) => address = Some(parse_cidr(cidr)?),
+            ("gateway", Value::String(ip)) => gateway = Some(parse_address(ip)?),
+            ("dns_server", Value::String(ip)) => dns_server = Some(parse_address(ip)?),
+            ("static_address", Value::Null) => address = None,
+            ("gateway", Value::Null) => gateway = None,
+            ("dns_server", Value::Null) => dns_server = None,
+            ("syslog_server", Value::String(ip)) => syslog_address = Some(parse_address(ip)?),
+            ("syslog_server", Value::Null) => syslog_address = None,
+            ("syslog_port", Value::Number(n)) => {
+                syslog_port = u16::try_from(n).ok().filter(|n| *n > 0)?;
+            }
+            ("syslog_min_severity", Value::String(name)) => {
+                min_severity = Severity::ALL.into_iter().find(|s| s.name() == name)?;
+            }
+            ("syslog_min_severity", Value::Number(n)) => {
+                min_severity = Severity::from_u8(u8::try_from(n).ok()?)?;
+            }
+            ("ntp_server", Value::String(ip)) => config.ntp_server = Some(parse_address(ip)?),
+            ("ntp_server", Value::Null) => config.ntp_server = None,
+            _ => return None,
+        }
+        Some(())
+    })?;
+
+    config.static_ipv4 = match address {
+        Some(address) => Some(StaticIpv4 {
+            address,
+            gateway,
+            dns_server,
+        }),
+        None if gateway.is_none() && dns_server.is_none() => None,
+        None => return None,
+    };
+    config.syslog = syslog_address.map(|address| SyslogServer {
+        address,
+        port: syslog_port,
+        min_severity,
+    });
+    Some(())
+}
+
+/// A unicast address in dotted decimal.
+fn parse_address(text: &str) -> Option<Ipv4Address> {
+    let address: Ipv4Address = text.parse().ok()?;
+    (!address.is_unspecified() && !address.is_broadcast() && !address.is_multicast())
+        .then_some(address)
+}
+
+/// An address and prefix length, such as `192.168.1.10/24`.
+fn parse_cidr(text: &str) -> Option<Ipv4Cidr> {
+    let (address, prefix_len) = text.split_once('/')?;
+    let prefix_len = prefix_len
+        .parse()
+        .ok()
+        .filter(|len| (1..=32).contains(len))?;
+    Some(Ipv4Cidr::new(parse_address(address)?, prefix_len))
+}
+
+/// Six pairs of hex digits separated by colons. Multicast addresses are refused, as they can't
+/// belong to a single device.
+fn parse_mac(text: &str) -> Option<[u8; 6]> {
+    let mut mac = [0; 6];
+    let mut parts = text.split(':');
+    for byte in &mut mac {
+        let part = parts
+            .next()
+            .filter(|part| part.len() == 2 && part.bytes().all(|b| b.is_ascii_hexdigit()))?;
+        *byte = u8::from_str_radix(part, 16).ok()?;
+    }
+
+    (parts.next().is_none() && mac[0] & 0x01 == 0).then_some(mac)
+}
+
+async fn update_settings(body: &str) -> Response {
+    // Check the whole body first so nothing is stored if any of it is invalid
+    if apply_settings(&mut config::get(), body).is_none() {
+        return Response::error("400 Bad Request");
+    }
+
+    if let Err(e) = config::update(|config| {
+        let _ = apply_settings(config, body);
+    })
+    .await
+    {
+        warn!("Failed to save settings: {}", e);
+        return Response::error("500 Internal Server Error");
+    }
+
+    info!("Settings changed over HTTP");
+
+    let mut response = Response::new("application/json");
+    write_settings(&mut response.body, &config::get());
+    response
+}
+
+fn write_mqtt(body: &mut Body, broker: Option<MqttBroker>) {
+    let _ = body.push_str("{\"address\":");
+    write_string_or_null(body, broker.map(|b| b.address));
+    let port = broker.map_or(mqtt::DEFAULT_PORT, |b| b.port);
+    let _ = write!(body, ",\"port\":{port}}}");
+}
+
+/// The broker set out in a `PUT /api/mqtt` body, returns `None` if it is invalid.
+fn parse_mqtt(broker: Option<MqttBroker>, body: &str) -> Option<Option<MqttBroker>> {
+    let mut address = broker.map(|b| b.address);
+    let mut port = broker.map_or(mqtt::DEFAULT_PORT, |b| b.port);
+
+    json::for_each_member(body, |key, value| {
+        match (key, value) {
+            ("address", Value::String(ip)) => address = Some(parse_address(ip)?),
+            ("address", Value::Null) => address = None,
+            ("port", Value::Number(n)) => port = u16::try_from(n).ok().filter(|n| *n > 0)?,
+            _ => return None,
+        }
+        Some(())
+    })?;
+
+    Some(address.map(|address| MqttBroker { address, port }))
+}
+
+async fn update_mqtt(body: &str) -> Response {
+    let current = config::get().mqtt_broker;
+    let Some(broker) = parse_mqtt(current, body) else {
+        return Response::error("400 Bad Request");
+    };
+
+    if broker != current {
+        if let Err(e) = config::update(|config| config.mqtt_broker = broker).await {
+            warn!("Failed to save MQTT broker: {}", e);
+            return Response::error("500 Internal Server Error");
+        }
+
+        info!("MQTT broker changed over HTTP");
+        mqtt::broker_changed();
+    }
+
+    let mut response = Response::new("application/json");
+    write_mqtt(&mut response.body, broker);
+    response
+}
+
+async fn handle_poll(request: &Request<'_>) -> Response {
+    let i = request.path["/api/polls/".len()..]
+        .parse::<usize>()
+        .ok()
+        .filter(|i| *i < MAX_POLLS);
+
+    match (request.method, i) {
+        (_, None) => Response::error("404 Not Found"),
+        ("DELETE", Some(i)) => clear_poll(i).await,
+        ("PUT", Some(i)) if request.body.trim() == "null" => clear_poll(i).await,
+        ("PUT", Some(i)) => update_poll(i, request.body).await,
+        _ => Response::error("405 Method Not Allowed"),
+    }
+}
+
+fn write_polls(body: &mut Body, polls: &[Option<Poll>]) {
+    let _ = body.push('[');
+    for (i, poll) in polls.iter().enumerate() {
+        if i > 0 {
+            let _ = body.push(',');
+        }
+        match poll {
+            Some(poll) => write_poll(body, poll),
+            None => {
+                let _ = body.push_str("null");
+            }
+        }
+    }
+    let _ = body.push(']');
+}
+
+fn write_poll(body: &mut Body, poll: &Poll) {
+    let _ = write!(
+        body,
+        "{{\"port\":{},\"address\":{},\"register_type\":\"{}\",\"start\":{},\"count\":{},\
+         \"interval_ms\":{},\"data_type\":\"{}\",\"word_order\":\"{}\",\"scale\":{}}}",
+        poll.port,
+        poll.address,
+        poll.register_type.name(),
+        poll.start,
+        poll.count,
+        poll.interval.as_millis(),
+        poll.data_type.name(),
+        poll.word_order.name(),
+        poll.scale
+    );
+}
+
+/// The poll set out in a `PUT /api/polls/<i>` body, with anything not given taken from `poll`.
+/// Returns `None` if the body is invalid or the poll can't be carried out.
+fn parse_poll(mut poll: Poll, body: &str) -> Option<Poll> {
+    json::for_each_member(body, |key, value| {
+        match (key, value) {
+            ("port", Value::Number(n)) => poll.port = usize::try_from(n).ok()?,
+            ("address", Value::Number(n)) => poll.address = u8::try_from(n).ok()?,
+            ("register_type", Value::String(name)) => {
+                poll.register_type = RegisterType::ALL.into_iter().find(|t| t.name() == name)?;
+            }
+            ("start", Value::Number(n)) => poll.start = u16::try_from(n).ok()?,
+            ("count", Value::Number(n)) => poll.count = u8::try_from(n).ok()?,
+            ("interval_ms", Value::Number(ms)) => {
+                poll.interval = Duration::from_millis(u32::try_from(ms).ok()? as u64);
+            }
+            ("data_type", Value::String(name)) => {
+                poll.data_type = DataType::ALL.into_iter().find(|t| t.name() == name)?;
+            }
+            ("word_order", Value::String(name)) => {
+                poll.word_order = WordOrder::ALL.into_iter().find(|o| o.name() == name)?;
+            }
+            ("scale", Value::Number(n)) => poll.scale = i8::try_from(n).ok()?,
+            _ => return None,
+        }
+        Some(())
+    })?;
+
+    poll.is_valid().then_some(poll)
+}
+
+async fn update_poll(i: usize, body: &str) -> Response {
+    // A new poll starts out invalid, so the body has to fill in the settings without a default
+    let poll = config::get().polls[i].unwrap_or(Poll {
+        port: 0,
+        address: 0,
+        register_type: RegisterType::Holding,
+        start: 0,
+        count: 0,
+        interval: Duration::from_ticks(0),
+        data_type: DataType::U16,
+        word_order: WordOrder::HighFirst,
+        scale: 0,
+    });
+    let Some(poll) = parse_poll(poll, body) else {
+        return Response::error("400 Bad Request");
+    };
+
+    if let Err(e) = config::update(|config| config.polls[i] = Some(poll)).await {
+        warn!("Failed to save poll {}: {}", i, e);
+        return Response::error("500 Internal Server Error");
+    }
+
+    info!("Poll {} changed over HTTP", i);
+
+    let mut response = Response::new("application/json");
+    write_poll(&mut response.body, &poll);
+    response
+}
+
+async fn clear_poll(i: usize) -> Response {
+    if let Err(e) = config::update(|config| config.polls[i] = None).await {
+        warn!("Failed to save poll {}: {}", i, e);
+        return Response::error("500 Internal Server Error");
+    }
+
+    info!("Poll {} cleared over HTTP", i);
+
+    let mut response = Response::new("application/json");
+    let _ = response.body.push_str("null");
+    response
+}
+
+fn status_page(stack: Stack<'_>) -> Response {
+    let mut response = Response::new("text/html");
+    let body = &mut response.body;
+
+    let hostname = identity::hostname();
+    let _ = write!(
+        body,
+        "<!DOCTYPE html><html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"5\"><title>{hostname}</title>\
+         <style>body{{font-family:sans-serif}}td,th{{padding:2px 12px;text-align:left}}</style>\
+         </head><body><h1>{hostname}</h1><table>\
+         <tr><th>MAC address</th><td>{}</td></tr>\
+         <tr><th>Link</th><td>{}</td></tr>",
+        stack.hardware_address(),
+        if stack.is_link_up() { "Up" } else { "Down" }
+    );
+
+    match IPV4_STATUS.try_get().flatten() {
+        Some(status) => {
+            let _ = write!(
+                body,
+                "<tr><th>IPv4 address</th><td>{} ({})</td></tr>",
+                status.address,
+                status.source.name()
+            );
+        }
+        None => {
+            let _ = body.push_str("<tr><th>IPv4 address</th><td>None</td></tr>");
+        }
+    }
+
+    let uptime = Instant::now().as_secs();
+    let _ = write!(
+        body,
+        "<tr><th>Uptime</th><td>{}d {:02}:{:02}:{:02}</td></tr></table>\
+         <h2>Ports</h2><table><tr><th>Port</th><th>Settings</th><th>Received</th>\
+         <th>Sent</th><th>Errors</th><th></th></tr>",
+        uptime / 86400,
+        uptime / 3600 % 24,
+        uptime / 60 % 60,
+        uptime % 60
+    );
+
+    for (n, port) in PORTS.iter().enumerate() {
+        let line = port.config();
+        let counts = stats::PORTS[n].get();
+        let parity = match line.parity {
+            Parity::ParityNone => 'N',
+            Parity::ParityOdd => 'O',
+            Parity::ParityEven => 'E',
+        };
+
+        let _ = write!(
+            body,
+            "<tr><td>{n}</td><td>{} {}{}{}</td><td>{}</td><td>{}</td><td>{}</td>\
+             <td><a href=\"/terminal?port={n}\">Terminal</a></td></tr>",
+            line.baudrate,
+            line.data_bit_count(),
+            parity,
+            line.stop_bit_count(),
+            counts.rx_bytes,
+            counts.tx_bytes,
+            counts.errors()
+        );
+    }
+
+    let _ = body.push_str("</table></body></html>");
+
+    response
+}
+
+fn metrics(stack: Stack<'_>) -> String<MAX_METRICS_SIZE> {
+    let mut body = String::new();
+
+    let network = [
+        (
+            "uptime_seconds",
+            "gauge",
+            "Time since the board was reset.",
+            Instant::now().as_secs() as u32,
+        ),
+        (
+            "link_up",
+            "gauge",
+            "Whether the Ethernet link is up.",
+            stack.is_link_up() as u32,
+        ),
+        (
+            "tcp_connections_total",
+            "counter",
+            "TCP connections accepted by all servers.",
+            stats::TCP_ACCEPTED.load(Ordering::Relaxed),
+        ),
+        (
+            "dhcp_leases_total",
+            "counter",
+            "DHCP leases obtained.",
+            stats::DHCP_LEASES.load(Ordering::Relaxed),
+        ),
+    ];
+    for (name, kind, help, value) in network {
+        write_family(&mut body, name, kind, help);
+        let _ = writeln!(body, "pi485_{name} {value}");
+    }
+
+    write_family(
+        &mut body,
+        "reset_reason",
+        "gauge",
+        "Why the board was last reset, the value is always 1.",
+    );
+    let _ = writeln!(
+        body,
+        "pi485_reset_reason{{reason=\"{}\"}} 1",
+        stats::reset_reason().name()
+    );
+
+    let counts = stats::PORTS.each_ref().map(|port| port.get());
+
+    let traffic = [
+        (
+            "rx_bytes",
+            "Bytes received on the line.",
+            counts.map(|c| c.rx_bytes),
+        ),
+        (
+            "tx_bytes",
+            "Bytes sent on the line.",
+            counts.map(|c| c.tx_bytes),
+        ),
+        (
+            "dropped_chunks",
+            "Chunks or frames of received data missed by a client that was not keeping up.",
+            counts.map(|c| c.dropped_chunks),
+        ),
+    ];
+    for (name, help, values) in traffic {
+        write_family(
+            &mut body,
+            format_args!("rs485_{name}_total"),
+            "counter",
+            help,
+        );
+        for (n, value) in values.iter().enumerate() {
+            let _ = writeln!(body, "pi485_rs485_{name}_total{{port=\"{n}\"}} {value}");
+        }
+    }
+
+    write_family(
+        &mut body,
+        "rs485_errors_total",
+        "counter",
+        "UART receive errors of each kind.",
+    );
+    for (n, counts) in counts.iter().enumerate() {
+        for (kind, value) in [
+            ("overrun", counts.overrun),
+            ("break", counts.break_),
+            ("parity", counts.parity),
+            ("framing", counts.framing),
+        ] {
+            let _ = writeln!(
+                body,
+                "pi485_rs485_errors_total{{port=\"{n}\",kind=\"{kind}\"}} {value}"
+            );
+        }
+    }
+
+    body
+}
+
+fn write_family(
+    body: &mut String<MAX_METRICS_SIZE>,
+    name: impl core::fmt::Display,
+    kind: &str,
+    help: &str,
+) {
+    let _ = write!(
+        body,
+        "# HELP pi485_{name} {help}\n# TYPE pi485_{name} {kind}\n"
+    );
+}
+
+fn parity_name(parity: Parity) -> &'static str {
+    match parity {
+        Parity::ParityNone => "none",
+        Parity::ParityOdd => "odd",
+        Parity::ParityEven => "even",
+    }
+}
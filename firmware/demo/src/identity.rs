@@ -0,0 +1,48 @@
+//! Identifiers unique to each board, derived from the unique ID of its flash chip.
+
+use crate::storage::Storage;
+use core::cell::Cell;
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::String;
+
+static UNIQUE_ID: Mutex<CriticalSectionRawMutex, Cell<[u8; 8]>> = Mutex::new(Cell::new([0; 8]));
+
+pub(crate) fn init(storage: &mut Storage) {
+    match storage.unique_id() {
+        Ok(id) => {
+            info!("Unique ID: {:02x}", id);
+            UNIQUE_ID.lock(|c| c.set(id));
+        }
+        Err(e) => warn!("Failed to read unique ID: {}", e),
+    }
+}
+
+/// A locally administered unicast MAC address made from the unique ID.
+pub(crate) fn mac_address() -> [u8; 6] {
+    let mut mac = [0x02, 0, 0, 0, 0, 0];
+    for (i, byte) in UNIQUE_ID.lock(Cell::get).iter().enumerate() {
+        mac[1 + i % 5] ^= byte;
+    }
+    mac
+}
+
+/// The unique ID in lowercase hex, identifying the board in network names and topics.
+pub(crate) fn id() -> String<16> {
+    const DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+    let mut id = String::new();
+    for byte in UNIQUE_ID.lock(Cell::get) {
+        let _ = id.push(DIGITS[(byte >> 4) as usize] as char);
+        let _ = id.push(DIGITS[(byte & 0xf) as usize] as char);
+    }
+    id
+}
+
+/// Network name of the board, `pi485-` followed by the unique ID in hex.
+pub(crate) fn hostname() -> String<32> {
+    let mut hostname = String::new();
+    let _ = hostname.push_str("pi485-");
+    let _ = hostname.push_str(&id());
+    hostname
+}
@@ -0,0 +1,70 @@
+//! Just enough JSON parsing for the HTTP API, which only takes flat objects.
+
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum Value<'a> {
+    /// String contents, which may not contain escapes.
+    String(&'a str),
+    Number(i64),
+    Bool(bool),
+    Null,
+}
+
+/// Call `f` with each member of an object whose values are not themselves objects or arrays.
+///
+/// Returns `None` if the text is not such an object or `f` returns `None`.
+pub(crate) fn for_each_member<'a>(
+    text: &'a str,
+    mut f: impl FnMut(&'a str, Value<'a>) -> Option<()>,
+) -> Option<()> {
+    let mut rest = text.trim().strip_prefix('{')?.trim_start();
+
+    if let Some(end) = rest.strip_prefix('}') {
+        return end.trim().is_empty().then_some(());
+    }
+
+    loop {
+        let (key, after) = string(rest)?;
+        let after = after.trim_start().strip_prefix(':')?.trim_start();
+        let (value, after) = value(after)?;
+        f(key, value)?;
+
+        let after = after.trim_start();
+        if let Some(after) = after.strip_prefix(',') {
+            rest = after.trim_start();
+        } else {
+            let after = after.strip_prefix('}')?;
+            return after.trim().is_empty().then_some(());
+        }
+    }
+}
+
+fn string(text: &str) -> Option<(&str, &str)> {
+    let text = text.strip_prefix('"')?;
+    let end = text.find(['"', '\\'])?;
+    text[end..]
+        .strip_prefix('"')
+        .map(|rest| (&text[..end], rest))
+}
+
+fn value(text: &str) -> Option<(Value<'_>, &str)> {
+    if text.starts_with('"') {
+        let (s, rest) = string(text)?;
+        return Some((Value::String(s), rest));
+    }
+
+    for (word, value) in [
+        ("true", Value::Bool(true)),
+        ("false", Value::Bool(false)),
+        ("null", Value::Null),
+    ] {
+        if let Some(rest) = text.strip_prefix(word) {
+            return Some((value, rest));
+        }
+    }
+
+    let end = text
+        .find(|c: char| !(c.is_ascii_digit() || c == '-'))
+        .unwrap_or(text.len());
+    let number = text[..end].parse().ok()?;
+    Some((Value::Number(number), &text[end..]))
+}
@@ -1,20 +1,45 @@
 #![no_std]
 #![no_main]
 
+mod backlight;
 mod buttons;
+mod clock;
+mod config;
+mod dashboard;
 mod display;
 mod ethernet;
+mod framing;
+mod http;
+mod identity;
+mod json;
+mod mdns;
+mod menu;
+mod modbus;
+mod modbus_tcp;
+mod monitor;
+mod mqtt;
+mod poller;
+mod rfc2217;
 mod rs485;
+mod serial_server;
+mod sntp;
+mod stats;
+mod status;
+mod storage;
+mod syslog;
+mod websocket;
 
 use defmt::info;
 use defmt_rtt as _;
 use embassy_executor::Spawner;
 use embassy_rp::{
+    gpio::{Input, Pull},
     peripherals::{self},
     spi::Spi,
     Peri,
 };
 use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
+use embassy_time::Timer;
 use panic_probe as _;
 use portable_atomic as _;
 use static_cell::StaticCell;
@@ -62,6 +87,9 @@ assign_resources::assign_resources! {
         b_pin: PIN_7,
         c_pin: PIN_8,
     }
+    storage: StorageResources {
+        flash: FLASH,
+    }
 }
 
 type SharedSpiInner = Spi<'static, peripherals::SPI0, embassy_rp::spi::Async>;
@@ -70,9 +98,31 @@ type SharedSpi = Mutex<CriticalSectionRawMutex, SharedSpiInner>;
 #[embassy_executor::main]
 async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
-    let r = split_resources!(p);
+    let mut r = split_resources!(p);
 
     info!("Hello, world!");
+    stats::init();
+
+    // Holding button A at power on restores the factory default settings
+    let factory_reset = {
+        let a = Input::new(r.buttons.a_pin.reborrow(), Pull::Up);
+        Timer::after_millis(10).await;
+        a.is_low()
+    };
+
+    let mut storage = storage::Storage::new(r.storage.flash);
+    identity::init(&mut storage);
+    config::init(storage, factory_reset).await;
+
+    let reset_reason = stats::reset_reason();
+    let severity = match reset_reason {
+        stats::ResetReason::Watchdog => syslog::Severity::Warning,
+        _ => syslog::Severity::Notice,
+    };
+    syslog::log(
+        severity,
+        format_args!("Started, reset reason: {}", reset_reason.name()),
+    );
 
     let mut spi_config = embassy_rp::spi::Config::default();
     spi_config.phase = embassy_rp::spi::Phase::CaptureOnSecondTransition;
@@ -97,4 +147,5 @@ async fn main(spawner: Spawner) {
     spawner.must_spawn(display::task(r.display));
     spawner.must_spawn(ethernet::task(spawner, spi, r.ethernet));
     spawner.must_spawn(rs485::task(r.rs485_uart_0, r.rs485_uart_1));
+    spawner.must_spawn(poller::task());
 }
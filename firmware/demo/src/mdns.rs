@@ -0,0 +1,486 @@
+//! Multicast DNS responder (RFC 6762) advertising the board and its services with DNS-SD
+//! (RFC 6763).
+//!
+//! Names are not probed for conflicts before use, the hostname contains the unique ID so should
+//! not clash with another board.
+
+use crate::{
+    ethernet::IPV4_STATUS, http, identity, modbus_tcp, rs485::PORT_COUNT, serial_server::Protocol,
+};
+use core::fmt::Write;
+use defmt::{debug, warn};
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Ipv4Address, Stack,
+};
+use heapless::{String, Vec};
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_ADDRESS: Ipv4Address = Ipv4Address::new(224, 0, 0, 251);
+
+const HOST_TTL: u32 = 120;
+const SERVICE_TTL: u32 = 4500;
+/// Longest TTL in a reply to a legacy unicast query, the querier has no way of hearing about
+/// changes so must not keep the records for long.
+const LEGACY_TTL: u32 = 10;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+
+const CLASS_IN: u16 = 1;
+/// Set in a record class when the record replaces any cached ones rather than adding to them.
+const CACHE_FLUSH: u16 = 0x8000;
+/// Set in a question class when the querier asks for a unicast response.
+const UNICAST_RESPONSE: u16 = 0x8000;
+
+const HEADER_SIZE: usize = 12;
+/// Largest packet that fits in an Ethernet frame with the IP and UDP headers, the announcement of
+/// every service takes more than the DNS limit.
+const MAX_PACKET_SIZE: usize = 1472;
+/// Largest reply to a legacy unicast query, which is held to the DNS limit.
+const MAX_LEGACY_PACKET_SIZE: usize = 512;
+/// Names and name suffixes in a response that later names can point back to.
+const MAX_NAMES: usize = 32;
+/// Compression pointers can only reach this far into a packet.
+const MAX_POINTER_OFFSET: usize = 0x3fff;
+
+const SERVICES_NAME: [&str; 4] = ["_services", "_dns-sd", "_udp", "local"];
+
+/// Service types offered, all are under `_tcp.local`.
+const SERVICE_TYPES: [&str; 4] = ["_http", "_modbus", "_pi485-raw", "_pi485-rfc2217"];
+
+const SERVICE_COUNT: usize = 1 + 3 * PORT_COUNT;
+
+struct Service {
+    service_type: &'static str,
+    instance: String<48>,
+    port: u16,
+}
+
+impl Service {
+    fn type_name(&self) -> [&str; 3] {
+        [self.service_type, "_tcp", "local"]
+    }
+
+    fn instance_name(&self) -> [&str; 4] {
+        [&self.instance, self.service_type, "_tcp", "local"]
+    }
+}
+
+fn services(hostname: &str) -> Vec<Service, SERVICE_COUNT> {
+    let mut services = Vec::new();
+
+    let mut instance = String::new();
+    let _ = instance.push_str(hostname);
+    let _ = services.push(Service {
+        service_type: "_http",
+        instance,
+        port: http::TCP_PORT,
+    });
+
+    for n in 0..PORT_COUNT {
+        let mut instance = String::new();
+        let _ = write!(instance, "{hostname} port {n}");
+        let _ = services.push(Service {
+            service_type: "_modbus",
+            instance,
+            port: modbus_tcp::tcp_port(n),
+        });
+    }
+
+    for (service_type, protocol) in [
+        ("_pi485-raw", Protocol::Raw),
+        ("_pi485-rfc2217", Protocol::Rfc2217),
+    ] {
+        for n in 0..PORT_COUNT {
+            let mut instance = String::new();
+            let _ = write!(instance, "{hostname} port {n}");
+            let _ = services.push(Service {
+                service_type,
+                instance,
+                port: protocol.tcp_port(n),
+            });
+        }
+    }
+
+    services
+}
+
+#[embassy_executor::task]
+pub(super) async fn task(stack: Stack<'static>) {
+    let hostname = identity::hostname();
+    let host_name = [hostname.as_str(), "local"];
+    let services = services(&hostname);
+
+    if let Err(e) = stack.join_multicast_group(MDNS_ADDRESS) {
+        warn!("Failed to join mDNS group: {}", e);
+    }
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 4];
+    let mut rx_buffer = [0; MAX_PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; MAX_PACKET_SIZE];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    if let Err(e) = socket.bind(MDNS_PORT) {
+        warn!("Failed to bind mDNS socket: {}", e);
+        return;
+    }
+
+    let Some(mut ipv4_status) = IPV4_STATUS.receiver() else {
+        warn!("No receiver left for the mDNS responder");
+        return;
+    };
+
+    let multicast = IpEndpoint::new(MDNS_ADDRESS.into(), MDNS_PORT);
+    let mut packet = [0; MAX_PACKET_SIZE];
+
+    loop {
+        let address = stack.config_v4().map(|config| config.address.address());
+
+        match select(socket.recv_from(&mut packet), ipv4_status.changed()).await {
+            Either::First(Ok((len, meta))) => {
+                let query = &packet[..len];
+
+                // Legacy unicast queries come from another port and expect a conventional reply
+                let legacy = meta.endpoint.port != MDNS_PORT;
+
+                let mut response = if legacy {
+                    Response::legacy(query)
+                } else {
+                    Response::new()
+                };
+                let unicast = answer(query, &mut response, &host_name, address, &services);
+
+                if response.overflow {
+                    warn!("mDNS response too large, not sent");
+                } else if response.answers > 0 {
+                    let destination = if unicast || legacy {
+                        meta.endpoint
+                    } else {
+                        multicast
+                    };
+                    debug!("mDNS response with {} answers", response.answers);
+                    let _ = socket.send_to(&response.buf, destination).await;
+                }
+            }
+            Either::First(Err(e)) => warn!("mDNS receive failed: {}", e),
+            Either::Second(Some(status)) => {
+                // Tell everyone listening about the new address straight away
+                let mut response = Response::new();
+                response.a(&host_name, status.address.address());
+                for service in &services {
+                    response.service(service, &host_name);
+                }
+                if response.overflow {
+                    warn!("mDNS announcement too large, not sent");
+                } else {
+                    let _ = socket.send_to(&response.buf, multicast).await;
+                }
+            }
+            Either::Second(None) => {}
+        }
+    }
+}
+
+fn query_id(query: &[u8]) -> u16 {
+    match query {
+        [b0, b1, ..] => u16::from_be_bytes([*b0, *b1]),
+        _ => 0,
+    }
+}
+
+/// Returns the offset following the question section of a query.
+fn skip_questions(query: &[u8]) -> Option<usize> {
+    let questions = u16::from_be_bytes([*query.get(4)?, *query.get(5)?]);
+    let mut offset = HEADER_SIZE;
+    for _ in 0..questions {
+        offset = skip_name(query, offset)? + 4;
+    }
+    (offset <= query.len()).then_some(offset)
+}
+
+/// Add answers to the questions in a query, returns true if a unicast response was requested.
+fn answer(
+    query: &[u8],
+    response: &mut Response,
+    host_name: &[&str],
+    address: Option<Ipv4Address>,
+    services: &[Service],
+) -> bool {
+    // Ignore responses and anything too short to be a query
+    if query.len() < HEADER_SIZE || query[2] & 0x80 != 0 {
+        return false;
+    }
+
+    let questions = u16::from_be_bytes([query[4], query[5]]);
+    let mut offset = HEADER_SIZE;
+    let mut unicast = false;
+    // The address is sent once however many questions it answers
+    let mut send_address = false;
+
+    for _ in 0..questions {
+        let name = offset;
+        let Some(end) = skip_name(query, offset) else {
+            break;
+        };
+        let Some(&[t0, t1, c0, c1]) = query.get(end..end + 4) else {
+            break;
+        };
+        offset = end + 4;
+
+        let qtype = u16::from_be_bytes([t0, t1]);
+        let qclass = u16::from_be_bytes([c0, c1]);
+        unicast |= qclass & UNICAST_RESPONSE != 0;
+
+        let wants = |rtype: u16| qtype == rtype || qtype == TYPE_ANY;
+        let matches = |labels: &[&str]| name_matches(query, name, labels);
+
+        if wants(TYPE_A) && matches(host_name) {
+            send_address = true;
+        }
+
+        if wants(TYPE_PTR) && matches(&SERVICES_NAME) {
+            for service_type in SERVICE_TYPES {
+                response.record(&SERVICES_NAME, TYPE_PTR, SERVICE_TTL, false, |r| {
+                    r.name(&[service_type, "_tcp", "local"])
+                });
+            }
+        }
+
+        for service in services {
+            if wants(TYPE_PTR) && matches(&service.type_name()) {
+                response.service(service, host_name);
+                send_address = true;
+            }
+
+            if matches(&service.instance_name()) {
+                if wants(TYPE_SRV) {
+                    response.srv(service, host_name);
+                }
+                if wants(TYPE_TXT) {
+                    response.txt(service);
+                }
+            }
+        }
+    }
+
+    if let Some(address) = address.filter(|_| send_address) {
+        response.a(host_name, address);
+    }
+
+    unicast
+}
+
+/// Returns the offset following a possibly compressed name.
+fn skip_name(packet: &[u8], mut offset: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(offset)?;
+        match len {
+            0 => return Some(offset + 1),
+            len if len & 0xc0 == 0xc0 => return Some(offset + 2),
+            len => offset += 1 + len as usize,
+        }
+    }
+}
+
+/// Compare a possibly compressed name with a list of labels, ignoring case.
+fn name_matches(packet: &[u8], mut offset: usize, labels: &[&str]) -> bool {
+    let mut labels = labels.iter();
+    let mut jumps = 0;
+
+    loop {
+        let Some(&len) = packet.get(offset) else {
+            return false;
+        };
+
+        match len {
+            0 => return labels.next().is_none(),
+            len if len & 0xc0 == 0xc0 => {
+                // Guard against pointer loops
+                jumps += 1;
+                let Some(&low) = packet.get(offset + 1) else {
+                    return false;
+                };
+                if jumps > 8 {
+                    return false;
+                }
+                offset = ((len as usize & 0x3f) << 8) | low as usize;
+            }
+            len => {
+                let len = len as usize;
+                let Some(label) = packet.get(offset + 1..offset + 1 + len) else {
+                    return false;
+                };
+                match labels.next() {
+                    Some(expected) if label.eq_ignore_ascii_case(expected.as_bytes()) => {
+                        offset += 1 + len;
+                    }
+                    _ => return false,
+                }
+            }
+        }
+    }
+}
+
+/// A response packet under construction. Names are compressed by pointing back to the longest
+/// suffix already written.
+struct Response {
+    buf: Vec<u8, MAX_PACKET_SIZE>,
+    /// Offsets of every name and suffix of a name written so far.
+    names: Vec<u16, MAX_NAMES>,
+    answers: u16,
+    /// Set for a reply to a legacy unicast query.
+    legacy: bool,
+    /// Set if the response did not fit, it must then not be sent.
+    overflow: bool,
+}
+
+impl Response {
+    fn new() -> Self {
+        let mut response = Self {
+            buf: Vec::new(),
+            names: Vec::new(),
+            answers: 0,
+            legacy: false,
+            overflow: false,
+        };
+
+        // Authoritative answer, all counts apart from the answers are zero
+        response.put(&[0; 2]);
+        response.put(&[0x84, 0x00]);
+        response.put(&[0; 8]);
+        response
+    }
+
+    /// A reply to a legacy unicast query, which carries the query ID and repeats its questions
+    /// as a conventional DNS reply does.
+    fn legacy(query: &[u8]) -> Self {
+        let mut response = Self::new();
+        response.legacy = true;
+        response.buf[0..2].copy_from_slice(&query_id(query).to_be_bytes());
+
+        if let Some(end) = skip_questions(query) {
+            // Names in the questions can only point back into the questions, which are at the
+            // same offset in the reply
+            response.put(&query[HEADER_SIZE..end]);
+            response.buf[4..6].copy_from_slice(&query[4..6]);
+        }
+        response
+    }
+
+    fn put(&mut self, data: &[u8]) {
+        let limit = if self.legacy {
+            MAX_LEGACY_PACKET_SIZE
+        } else {
+            MAX_PACKET_SIZE
+        };
+        if self.buf.len() + data.len() > limit || self.buf.extend_from_slice(data).is_err() {
+            self.overflow = true;
+        }
+    }
+
+    fn name(&mut self, labels: &[&str]) {
+        for (i, label) in labels.iter().enumerate() {
+            let suffix = &labels[i..];
+            let earlier = self
+                .names
+                .iter()
+                .find(|offset| name_matches(&self.buf, **offset as usize, suffix));
+            if let Some(&offset) = earlier {
+                self.put(&(0xc000 | offset).to_be_bytes());
+                return;
+            }
+
+            let offset = self.buf.len();
+            if offset <= MAX_POINTER_OFFSET {
+                let _ = self.names.push(offset as u16);
+            }
+            self.put(&[label.len() as u8]);
+            self.put(label.as_bytes());
+        }
+        self.put(&[0]);
+    }
+
+    fn record(
+        &mut self,
+        name: &[&str],
+        rtype: u16,
+        ttl: u32,
+        unique: bool,
+        rdata: impl FnOnce(&mut Self),
+    ) {
+        // Legacy queriers don't know about the cache flush bit, nor about updates to the records
+        let class = if unique && !self.legacy {
+            CLASS_IN | CACHE_FLUSH
+        } else {
+            CLASS_IN
+        };
+        let ttl = if self.legacy {
+            ttl.min(LEGACY_TTL)
+        } else {
+            ttl
+        };
+
+        self.name(name);
+        self.put(&rtype.to_be_bytes());
+        self.put(&class.to_be_bytes());
+        self.put(&ttl.to_be_bytes());
+
+        let len_offset = self.buf.len();
+        self.put(&[0, 0]);
+        rdata(self);
+
+        if self.overflow {
+            return;
+        }
+
+        let len = (self.buf.len() - len_offset - 2) as u16;
+        self.buf[len_offset..len_offset + 2].copy_from_slice(&len.to_be_bytes());
+
+        self.answers += 1;
+        self.buf[6..8].copy_from_slice(&self.answers.to_be_bytes());
+    }
+
+    fn a(&mut self, host_name: &[&str], address: Ipv4Address) {
+        self.record(host_name, TYPE_A, HOST_TTL, true, |r| {
+            r.put(&address.octets())
+        });
+    }
+
+    /// The PTR record pointing at a service instance, along with its SRV and TXT records.
+    fn service(&mut self, service: &Service, host_name: &[&str]) {
+        self.record(&service.type_name(), TYPE_PTR, SERVICE_TTL, false, |r| {
+            r.name(&service.instance_name())
+        });
+        self.srv(service, host_name);
+        self.txt(service);
+    }
+
+    fn srv(&mut self, service: &Service, host_name: &[&str]) {
+        self.record(&service.instance_name(), TYPE_SRV, HOST_TTL, true, |r| {
+            // Priority and weight
+            r.put(&[0, 0, 0, 0]);
+            r.put(&service.port.to_be_bytes());
+            r.name(host_name);
+        });
+    }
+
+    fn txt(&mut self, service: &Service) {
+        self.record(&service.instance_name(), TYPE_TXT, SERVICE_TTL, true, |r| {
+            // A single empty string, as there is nothing to say about the service
+            r.put(&[0])
+        });
+    }
+}
@@ -0,0 +1,463 @@
+//! Menus and screens shown on the display, driven by the buttons.
+//!
+//! Button A moves to the next item, B selects it and C goes back. In the settings editor A moves
+//! through the values, B saves the highlighted one and C leaves the setting as it was. On the
+//! traffic monitor A moves to the next port and B pauses or resumes it.
+
+use crate::{
+    buttons::Button,
+    clock,
+    config::{self, Ipv4Mode},
+    ethernet, identity,
+    monitor::View,
+    rs485::{LineConfig, PORTS, PORT_COUNT},
+    stats,
+    status::Status,
+};
+use core::fmt::{Arguments, Write as _};
+use defmt::{info, warn};
+use embassy_rp::uart::Parity;
+use embassy_time::Duration;
+use heapless::{String, Vec};
+
+/// Characters in a row of the display.
+pub(crate) const COLUMNS: usize = 24;
+/// Rows of the display, including the title.
+pub(crate) const ROWS: usize = 12;
+
+pub(crate) type Row = String<COLUMNS>;
+
+/// Everything on the display while in the menus or on the dashboard.
+#[derive(Clone)]
+pub(crate) struct Page {
+    pub(crate) title: Row,
+    pub(crate) rows: Vec<Row, { ROWS - 1 }>,
+    /// Row of the highlighted item.
+    pub(crate) selected: Option<usize>,
+}
+
+const BAUDRATES: [u32; 11] = [
+    1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600,
+];
+const PARITIES: [Parity; 3] = [Parity::ParityNone, Parity::ParityEven, Parity::ParityOdd];
+const IPV4_MODES: [Ipv4Mode; 2] = [Ipv4Mode::Dhcp, Ipv4Mode::Static];
+/// Backlight brightness in percent.
+const BRIGHTNESSES: [u8; 5] = [10, 25, 50, 75, 100];
+/// Time without a button press before the backlight dims, `None` keeps it lit.
+const IDLE_TIMEOUTS: [Option<Duration>; 4] = [
+    None,
+    Some(Duration::from_secs(30)),
+    Some(Duration::from_secs(60)),
+    Some(Duration::from_secs(300)),
+];
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Screen {
+    /// The dashboard, left on any button.
+    Home,
+    Main,
+    Status,
+    Network,
+    Port(usize),
+    /// The traffic monitor, drawn from [`Menu::monitor`] rather than a page.
+    Monitor,
+    Settings,
+    /// Changing the setting at this index of the settings list.
+    Edit(usize),
+}
+
+fn main_menu() -> Vec<Screen, { 4 + PORT_COUNT }> {
+    let mut items = Vec::new();
+    let _ = items.push(Screen::Status);
+    let _ = items.push(Screen::Network);
+    for n in 0..PORT_COUNT {
+        let _ = items.push(Screen::Port(n));
+    }
+    let _ = items.push(Screen::Monitor);
+    let _ = items.push(Screen::Settings);
+    items
+}
+
+#[derive(Clone, Copy)]
+enum Setting {
+    Baudrate(usize),
+    Parity(usize),
+    Ipv4Mode,
+    Brightness,
+    IdleTimeout,
+}
+
+fn settings() -> Vec<Setting, { 2 * PORT_COUNT + 3 }> {
+    let mut items = Vec::new();
+    for n in 0..PORT_COUNT {
+        let _ = items.push(Setting::Baudrate(n));
+        let _ = items.push(Setting::Parity(n));
+    }
+    let _ = items.push(Setting::Ipv4Mode);
+    let _ = items.push(Setting::Brightness);
+    let _ = items.push(Setting::IdleTimeout);
+    items
+}
+
+impl Setting {
+    fn label(self) -> Row {
+        match self {
+            Setting::Baudrate(n) => row(format_args!("Port {n} baud rate")),
+            Setting::Parity(n) => row(format_args!("Port {n} parity")),
+            Setting::Ipv4Mode => row(format_args!("IPv4 mode")),
+            Setting::Brightness => row(format_args!("Brightness")),
+            Setting::IdleTimeout => row(format_args!("Dim after")),
+        }
+    }
+
+    fn choice_count(self) -> usize {
+        match self {
+            Setting::Baudrate(_) => BAUDRATES.len(),
+            Setting::Parity(_) => PARITIES.len(),
+            Setting::Ipv4Mode => IPV4_MODES.len(),
+            Setting::Brightness => BRIGHTNESSES.len(),
+            Setting::IdleTimeout => IDLE_TIMEOUTS.len(),
+        }
+    }
+
+    fn choice_name(self, i: usize) -> Row {
+        match self {
+            Setting::Baudrate(_) => row(format_args!("{}", BAUDRATES[i])),
+            Setting::Parity(_) => row(format_args!("{}", parity_name(PARITIES[i]))),
+            Setting::Ipv4Mode => row(format_args!("{}", ipv4_mode_name(IPV4_MODES[i]))),
+            Setting::Brightness => row(format_args!("{}%", BRIGHTNESSES[i])),
+            Setting::IdleTimeout => match IDLE_TIMEOUTS[i] {
+                None => row(format_args!("Never")),
+                Some(timeout) if timeout.as_secs() < 60 => {
+                    row(format_args!("{} s", timeout.as_secs()))
+                }
+                Some(timeout) => row(format_args!("{} min", timeout.as_secs() / 60)),
+            },
+        }
+    }
+
+    /// The choice matching the current value, or the closest one for a baud rate, brightness or
+    /// idle timeout not in the list.
+    fn current(self) -> usize {
+        match self {
+            Setting::Baudrate(n) => {
+                let baudrate = PORTS[n].config().baudrate;
+                BAUDRATES
+                    .iter()
+                    .position(|b| *b >= baudrate)
+                    .unwrap_or(BAUDRATES.len() - 1)
+            }
+            Setting::Parity(n) => {
+                let parity = PORTS[n].config().parity;
+                PARITIES.iter().position(|p| *p == parity).unwrap_or(0)
+            }
+            Setting::Ipv4Mode => {
+                let mode = config::get().ipv4_mode;
+                IPV4_MODES.iter().position(|m| *m == mode).unwrap_or(0)
+            }
+            Setting::Brightness => {
+                let brightness = config::get().backlight.brightness;
+                BRIGHTNESSES
+                    .iter()
+                    .position(|b| *b >= brightness)
+                    .unwrap_or(BRIGHTNESSES.len() - 1)
+            }
+            Setting::IdleTimeout => {
+                // `None` comes before any timeout, as it does in the list
+                let timeout = config::get().backlight.idle_timeout;
+                IDLE_TIMEOUTS
+                    .iter()
+                    .position(|t| *t >= timeout)
+                    .unwrap_or(IDLE_TIMEOUTS.len() - 1)
+            }
+        }
+    }
+
+    fn change(self, i: usize) -> Change {
+        match self {
+            Setting::Baudrate(n) => Change::Line(
+                n,
+                LineConfig {
+                    baudrate: BAUDRATES[i],
+                    ..PORTS[n].config()
+                },
+            ),
+            Setting::Parity(n) => Change::Line(
+                n,
+                LineConfig {
+                    parity: PARITIES[i],
+                    ..PORTS[n].config()
+                },
+            ),
+            Setting::Ipv4Mode => Change::Ipv4Mode(IPV4_MODES[i]),
+            Setting::Brightness => Change::Brightness(BRIGHTNESSES[i]),
+            Setting::IdleTimeout => Change::IdleTimeout(IDLE_TIMEOUTS[i]),
+        }
+    }
+}
+
+/// A setting changed in the editor.
+pub(crate) enum Change {
+    Line(usize, LineConfig),
+    /// Static is refused while there is no static address to use.
+    Ipv4Mode(Ipv4Mode),
+    Brightness(u8),
+    IdleTimeout(Option<Duration>),
+}
+
+pub(crate) struct Menu {
+    screen: Screen,
+    main_item: usize,
+    setting_item: usize,
+    /// Value highlighted in the editor.
+    choice: usize,
+    monitor: View,
+}
+
+impl Menu {
+    pub(crate) const fn new() -> Self {
+        Self {
+            screen: Screen::Home,
+            main_item: 0,
+            setting_item: 0,
+            choice: 0,
+            monitor: View::new(),
+        }
+    }
+
+    pub(crate) fn is_home(&self) -> bool {
+        self.screen == Screen::Home
+    }
+
+    /// The traffic monitor, if that is the current screen.
+    pub(crate) fn monitor(&self) -> Option<&View> {
+        (self.screen == Screen::Monitor).then_some(&self.monitor)
+    }
+
+    /// Act on a button, returns a setting to change if one was saved.
+    pub(crate) fn press(&mut self, button: Button) -> Option<Change> {
+        let main_menu = main_menu();
+        let settings = settings();
+
+        match (self.screen, button) {
+            (Screen::Home, _) => self.screen = Screen::Main,
+            (Screen::Main, Button::A) => self.main_item = (self.main_item + 1) % main_menu.len(),
+            (Screen::Main, Button::B) => self.screen = main_menu[self.main_item],
+            (Screen::Main, Button::C) => self.screen = Screen::Home,
+            (Screen::Settings, Button::A) => {
+                self.setting_item = (self.setting_item + 1) % settings.len();
+            }
+            (Screen::Settings, Button::B) => {
+                self.choice = settings[self.setting_item].current();
+                self.screen = Screen::Edit(self.setting_item);
+            }
+            (Screen::Edit(i), Button::A) => {
+                self.choice = (self.choice + 1) % settings[i].choice_count();
+            }
+            (Screen::Edit(i), Button::B) => {
+                self.screen = Screen::Settings;
+                return Some(settings[i].change(self.choice));
+            }
+            (Screen::Edit(_), Button::C) => self.screen = Screen::Settings,
+            (Screen::Monitor, Button::A) => self.monitor.next_port(),
+            (Screen::Monitor, Button::B) => self.monitor.toggle_pause(),
+            (_, Button::C) => self.screen = Screen::Main,
+            _ => {}
+        }
+
+        None
+    }
+
+    /// What to show for the current screen, which must not be the home or monitor screen.
+    pub(crate) fn page(&self, status: &Status) -> Page {
+        let mut page = Page {
+            title: Row::new(),
+            rows: Vec::new(),
+            selected: None,
+        };
+        let mut add = |args: Arguments| {
+            let _ = page.rows.push(row(args));
+        };
+
+        match self.screen {
+            Screen::Home | Screen::Monitor => {}
+            Screen::Main => {
+                for item in main_menu() {
+                    match item {
+                        Screen::Status => add(format_args!("Status")),
+                        Screen::Network => add(format_args!("Network")),
+                        Screen::Port(n) => add(format_args!("Port {n}")),
+                        Screen::Monitor => add(format_args!("Monitor")),
+                        _ => add(format_args!("Settings")),
+                    }
+                }
+                page.title = row(format_args!("Menu"));
+                page.selected = Some(self.main_item);
+            }
+            Screen::Status => {
+                let uptime = status.time.as_secs();
+                add(format_args!("{}", identity::hostname()));
+                add(format_args!(
+                    "Up {}d {:02}:{:02}:{:02}",
+                    uptime / 86400,
+                    uptime / 3600 % 24,
+                    uptime / 60 % 60,
+                    uptime % 60
+                ));
+                add(format_args!(
+                    "Link {}",
+                    if status.link_up { "up" } else { "down" }
+                ));
+                match status.ipv4 {
+                    Some(ipv4) => add(format_args!("{}", ipv4.address)),
+                    None => add(format_args!("No address")),
+                }
+                match clock::now() {
+                    Some(time) => {
+                        let secs = time.unix_secs();
+                        add(format_args!(
+                            "{:02}:{:02}:{:02} UTC",
+                            secs / 3600 % 24,
+                            secs / 60 % 60,
+                            secs % 60
+                        ))
+                    }
+                    None => add(format_args!("Clock not set")),
+                }
+                page.title = row(format_args!("Status"));
+            }
+            Screen::Network => {
+                let config = config::get();
+                add(format_args!("Mode {}", ipv4_mode_name(config.ipv4_mode)));
+                match status.ipv4 {
+                    Some(ipv4) => {
+                        add(format_args!("{}", ipv4.address));
+                        add(format_args!("From {}", ipv4.source.name()));
+                    }
+                    None => add(format_args!("No address")),
+                }
+                add(format_args!("MAC address"));
+                let mac = config.mac_address.unwrap_or_else(identity::mac_address);
+                let mut text = Row::new();
+                for (i, byte) in mac.iter().enumerate() {
+                    let separator = if i > 0 { ":" } else { "" };
+                    let _ = write!(text, "{separator}{byte:02x}");
+                }
+                add(format_args!("{text}"));
+                page.title = row(format_args!("Network"));
+            }
+            Screen::Port(n) => {
+                let line = PORTS[n].config();
+                let counts = stats::PORTS[n].get();
+                add(format_args!(
+                    "{} {}{}{}",
+                    line.baudrate,
+                    line.data_bit_count(),
+                    parity_letter(line.parity),
+                    line.stop_bit_count()
+                ));
+                add(format_args!("RX {} B", counts.rx_bytes));
+                add(format_args!("TX {} B", counts.tx_bytes));
+                add(format_args!("Errors {}", counts.errors()));
+                add(format_args!("Dropped {}", counts.dropped_chunks));
+                page.title = row(format_args!("Port {n}"));
+            }
+            Screen::Settings => {
+                for setting in settings() {
+                    let value = setting.choice_name(setting.current());
+                    add(format_args!("{}: {}", setting.label(), value));
+                }
+                page.title = row(format_args!("Settings"));
+                page.selected = Some(self.setting_item);
+            }
+            Screen::Edit(i) => {
+                let setting = settings()[i];
+                for choice in 0..setting.choice_count() {
+                    add(format_args!("{}", setting.choice_name(choice)));
+                }
+                if let (Setting::Ipv4Mode, None) = (setting, config::get().static_ipv4) {
+                    add(format_args!(""));
+                    add(format_args!("Static needs an"));
+                    add(format_args!("address set over HTTP"));
+                }
+                page.title = setting.label();
+                page.selected = Some(self.choice);
+            }
+        }
+
+        page
+    }
+}
+
+/// Store a setting changed in the editor, and apply it if that can be done straight away.
+pub(crate) async fn apply(change: Change) {
+    let res = match change {
+        Change::Line(n, line) => {
+            PORTS[n].set_config(line);
+            info!("Port {} settings changed on the display", n);
+            config::update(|config| config.ports[n] = line).await
+        }
+        Change::Ipv4Mode(Ipv4Mode::Static) if config::get().static_ipv4.is_none() => {
+            warn!("No static address is stored, IPv4 mode left as it is");
+            return;
+        }
+        Change::Ipv4Mode(mode) => {
+            info!("IPv4 mode changed on the display: {}", mode);
+            let res = config::update(|config| config.ipv4_mode = mode).await;
+            if res.is_ok() {
+                ethernet::ipv4_changed();
+            }
+            res
+        }
+        Change::Brightness(brightness) => {
+            info!("Brightness changed on the display: {}%", brightness);
+            config::update(|config| config.backlight.brightness = brightness).await
+        }
+        Change::IdleTimeout(timeout) => {
+            info!(
+                "Backlight idle timeout changed on the display: {} s",
+                timeout.map_or(0, |t| t.as_secs())
+            );
+            config::update(|config| config.backlight.idle_timeout = timeout).await
+        }
+    };
+
+    if let Err(e) = res {
+        warn!("Failed to save settings: {}", e);
+    }
+}
+
+/// Text cut short to fit a row.
+pub(crate) fn row(args: Arguments) -> Row {
+    let mut text = String::<{ 2 * COLUMNS }>::new();
+    let _ = text.write_fmt(args);
+
+    let mut row = Row::new();
+    for c in text.chars().take(COLUMNS) {
+        let _ = row.push(c);
+    }
+    row
+}
+
+fn ipv4_mode_name(mode: Ipv4Mode) -> &'static str {
+    match mode {
+        Ipv4Mode::Dhcp => "DHCP",
+        Ipv4Mode::Static => "Static",
+    }
+}
+
+fn parity_name(parity: Parity) -> &'static str {
+    match parity {
+        Parity::ParityNone => "None",
+        Parity::ParityEven => "Even",
+        Parity::ParityOdd => "Odd",
+    }
+}
+
+pub(crate) fn parity_letter(parity: Parity) -> char {
+    match parity {
+        Parity::ParityNone => 'N',
+        Parity::ParityEven => 'E',
+        Parity::ParityOdd => 'O',
+    }
+}
@@ -0,0 +1,125 @@
+use crate::{
+    framing::{Framing, IdleGap},
+    rs485::BusGuard,
+};
+use defmt::{debug, Format};
+use embassy_sync::pubsub::WaitResult;
+use embassy_time::{with_timeout, Duration, Timer};
+use heapless::Vec;
+
+/// Largest RTU frame: address, 253 byte PDU and CRC.
+pub(crate) const MAX_ADU_SIZE: usize = 256;
+pub(crate) const MAX_PDU_SIZE: usize = MAX_ADU_SIZE - 3;
+
+pub(crate) type Pdu = Vec<u8, MAX_PDU_SIZE>;
+
+pub(crate) const BROADCAST_ADDRESS: u8 = 0;
+
+/// Exception code used when the RS485 port cannot be used.
+pub(crate) const GATEWAY_PATH_UNAVAILABLE: u8 = 0x0a;
+/// Exception code used when the addressed device did not give a valid response.
+pub(crate) const GATEWAY_TARGET_FAILED_TO_RESPOND: u8 = 0x0b;
+
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(1000);
+const BROADCAST_TURNAROUND: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Format)]
+pub(crate) enum Error {
+    /// The port has no free receivers.
+    Unavailable,
+    /// No response was received.
+    Timeout,
+    /// Received data was lost before the response was read.
+    Lagged,
+    /// The response was longer than a valid RTU frame.
+    Overflow,
+    /// The response failed the CRC check or was too short.
+    InvalidFrame,
+    /// The response came from a different device.
+    UnexpectedAddress(u8),
+}
+
+/// CRC16 as used by Modbus RTU (polynomial 0xA001, initial value 0xFFFF).
+pub(crate) fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xffff_u16;
+    for byte in data {
+        crc ^= *byte as u16;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xa001;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    crc
+}
+
+/// RTU frames are separated by 3.5 characters of silence, above 19200 baud the spec fixes this at
+/// 1750us.
+pub(crate) fn rtu_framing(baudrate: u32) -> Framing {
+    let idle_gap = if baudrate > 19200 {
+        IdleGap::Micros(1750)
+    } else {
+        IdleGap::CharTimes(35)
+    };
+
+    Framing {
+        idle_gap,
+        max_len: MAX_ADU_SIZE,
+    }
+}
+
+/// Send a request PDU to a device and wait for its response PDU.
+///
+/// The port is switched to RTU framing until the bus lock is released. Requests to the broadcast
+/// address return an empty response after the turnaround delay.
+pub(crate) async fn transact(
+    bus: &BusGuard<'_>,
+    address: u8,
+    request: &[u8],
+) -> Result<Pdu, Error> {
+    let port = bus.port();
+
+    let mut frame = Vec::<u8, MAX_ADU_SIZE>::new();
+    frame.push(address).map_err(|_| Error::Overflow)?;
+    frame
+        .extend_from_slice(request)
+        .map_err(|_| Error::Overflow)?;
+    let crc = crc16(&frame);
+    frame
+        .extend_from_slice(&crc.to_le_bytes())
+        .map_err(|_| Error::Overflow)?;
+
+    let config = port.config();
+    bus.set_framing(rtu_framing(config.baudrate));
+    let tx_time = config.char_time() * frame.len() as u32;
+
+    // Subscribe before sending so a fast response is not missed
+    let mut rx = port.subscribe().ok_or(Error::Unavailable)?;
+
+    debug!("RTU request: {:x}", frame);
+    port.write(&frame).await;
+
+    if address == BROADCAST_ADDRESS {
+        Timer::after(tx_time + BROADCAST_TURNAROUND).await;
+        return Ok(Pdu::new());
+    }
+
+    let response = match with_timeout(tx_time + RESPONSE_TIMEOUT, rx.next_message()).await {
+        Ok(WaitResult::Message(response)) => response,
+        Ok(WaitResult::Lagged(_)) => return Err(Error::Lagged),
+        Err(_) => return Err(Error::Timeout),
+    };
+
+    debug!("RTU response: {:x}", response);
+
+    if response.len() < 4 || crc16(&response) != 0 {
+        return Err(Error::InvalidFrame);
+    }
+    if response[0] != address {
+        return Err(Error::UnexpectedAddress(response[0]));
+    }
+
+    Pdu::from_slice(&response[1..response.len() - 2]).map_err(|_| Error::Overflow)
+}
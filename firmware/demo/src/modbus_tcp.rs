@@ -0,0 +1,152 @@
+//! Modbus TCP to RTU gateway.
+//!
+//! Each RS485 port has its own listener, port 0 on the standard TCP port 502 and each following
+//! port on the next TCP port up, so port 1 is reached on 503. Requests are forwarded with their
+//! unit ID as the device address.
+
+use crate::{
+    modbus::{self, Pdu, MAX_PDU_SIZE},
+    rs485::{PORTS, PORT_COUNT},
+    stats,
+    syslog::{self, Severity},
+};
+use defmt::{info, warn};
+use embassy_futures::select::select;
+use embassy_net::{tcp::TcpSocket, IpEndpoint, Stack};
+use embassy_time::Duration;
+use embedded_io_async::{Read, ReadExactError, Write};
+
+/// TCP port serving RS485 port 0.
+const BASE_TCP_PORT: u16 = 502;
+
+/// Number of Modbus TCP clients that can be connected to each port at once.
+const CLIENTS_PER_PORT: usize = 2;
+
+pub(crate) const TASKS: usize = PORT_COUNT * CLIENTS_PER_PORT;
+
+/// TCP port serving RS485 port `n`.
+pub(crate) fn tcp_port(n: usize) -> u16 {
+    BASE_TCP_PORT + n as u16
+}
+
+const MBAP_HEADER_SIZE: usize = 7;
+
+#[embassy_executor::task(pool_size = TASKS)]
+pub(super) async fn task(stack: Stack<'static>, n: usize) {
+    let mut rx_buffer = [0; 512];
+    let mut tx_buffer = [0; 512];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(60)));
+
+        if let Err(e) = socket.accept(tcp_port(n)).await {
+            warn!("Modbus TCP accept failed: {}", e);
+            continue;
+        }
+        stats::record_accept();
+
+        let remote = socket.remote_endpoint();
+        info!("Modbus TCP client connected to port {}: {}", n, remote);
+        log_client(n, remote, "connected");
+
+        // The connection cannot survive the link going down, or the board moving network
+        let _ = select(serve(&mut socket, n), stack.wait_link_down()).await;
+        info!("Modbus TCP client disconnected from port {}", n);
+        log_client(n, remote, "disconnected");
+
+        socket.abort();
+        let _ = socket.flush().await;
+    }
+}
+
+fn log_client(n: usize, remote: Option<IpEndpoint>, event: &str) {
+    if let Some(remote) = remote {
+        syslog::log(
+            Severity::Informational,
+            format_args!("Modbus TCP client {remote} {event} on port {n}"),
+        );
+    }
+}
+
+struct Disconnected {}
+
+impl From<embassy_net::tcp::Error> for Disconnected {
+    fn from(_: embassy_net::tcp::Error) -> Self {
+        Disconnected {}
+    }
+}
+
+impl From<ReadExactError<embassy_net::tcp::Error>> for Disconnected {
+    fn from(_: ReadExactError<embassy_net::tcp::Error>) -> Self {
+        Disconnected {}
+    }
+}
+
+async fn serve(socket: &mut TcpSocket<'_>, n: usize) -> Result<(), Disconnected> {
+    let port = &PORTS[n];
+
+    let mut header = [0u8; MBAP_HEADER_SIZE];
+    let mut request = [0u8; MAX_PDU_SIZE];
+
+    loop {
+        socket.read_exact(&mut header).await?;
+
+        let transaction_id = [header[0], header[1]];
+        let protocol_id = u16::from_be_bytes([header[2], header[3]]);
+        let length = u16::from_be_bytes([header[4], header[5]]) as usize;
+        let unit_id = header[6];
+
+        // Length covers the unit ID and the PDU
+        if protocol_id != 0 || length < 2 || length - 1 > MAX_PDU_SIZE {
+            warn!("Invalid MBAP header: {:x}", header);
+            return Err(Disconnected {});
+        }
+
+        let request = &mut request[..length - 1];
+        socket.read_exact(request).await?;
+
+        let response = {
+            let bus = port.lock_bus().await;
+            modbus::transact(&bus, unit_id, request).await
+        };
+
+        let response = match response {
+            // Broadcast requests get no response
+            Ok(_) if unit_id == modbus::BROADCAST_ADDRESS => continue,
+            Ok(response) => response,
+            Err(e) => {
+                warn!(
+                    "Modbus RTU request to unit {} on port {} failed: {}",
+                    unit_id, n, e
+                );
+                let code = match e {
+                    modbus::Error::Unavailable => modbus::GATEWAY_PATH_UNAVAILABLE,
+                    _ => modbus::GATEWAY_TARGET_FAILED_TO_RESPOND,
+                };
+                exception(request[0], code)
+            }
+        };
+
+        let length = (response.len() as u16 + 1).to_be_bytes();
+        let header = [
+            transaction_id[0],
+            transaction_id[1],
+            0,
+            0,
+            length[0],
+            length[1],
+            unit_id,
+        ];
+
+        socket.write_all(&header).await?;
+        socket.write_all(&response).await?;
+    }
+}
+
+fn exception(function: u8, code: u8) -> Pdu {
+    let mut pdu = Pdu::new();
+    let _ = pdu.push(function | 0x80);
+    let _ = pdu.push(code);
+    pdu
+}
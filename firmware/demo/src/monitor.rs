@@ -0,0 +1,200 @@
+//! Recent traffic on each port, kept for the monitor screen on the display.
+//!
+//! Frames are taken from the RS485 receive and transmit paths as they pass, with only the start
+//! of long ones kept. The screen lists them oldest first in hex and ASCII, each with its
+//! direction and the time since the frame before it.
+
+use crate::rs485::PORT_COUNT;
+use core::{cell::RefCell, fmt::Write as _};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::{Duration, Instant};
+use heapless::{Deque, String, Vec};
+
+/// Characters in a line of the monitor screen.
+pub(crate) const COLUMNS: usize = 40;
+/// Lines of the monitor screen, including the title.
+pub(crate) const ROWS: usize = 24;
+
+/// Bytes kept of each frame, longer frames are cut short.
+const CAPTURE_SIZE: usize = 32;
+/// Frames kept for each port, enough to fill the screen with short ones.
+const FRAME_COUNT: usize = 12;
+const BYTES_PER_LINE: usize = 8;
+
+pub(crate) type Line = String<COLUMNS>;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Rx,
+    Tx,
+}
+
+#[derive(Clone)]
+struct Frame {
+    direction: Direction,
+    /// Time since the previous frame on the port, `None` for the first one.
+    gap: Option<Duration>,
+    /// Length of the whole frame, which may be more than was kept.
+    len: usize,
+    data: Vec<u8, CAPTURE_SIZE>,
+}
+
+type Frames = Deque<Frame, FRAME_COUNT>;
+
+struct Capture {
+    frames: Frames,
+    last: Option<Instant>,
+}
+
+static CAPTURES: [Mutex<CriticalSectionRawMutex, RefCell<Capture>>; PORT_COUNT] = [const {
+    Mutex::new(RefCell::new(Capture {
+        frames: Deque::new(),
+        last: None,
+    }))
+}; PORT_COUNT];
+
+static RECORDED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Keep a frame sent or received on port `n`, in place of the oldest one.
+pub(crate) fn record(n: usize, direction: Direction, data: &[u8]) {
+    let now = Instant::now();
+
+    CAPTURES[n].lock(|c| {
+        let mut capture = c.borrow_mut();
+        let gap = capture.last.map(|last| now.saturating_duration_since(last));
+        capture.last = Some(now);
+
+        if capture.frames.is_full() {
+            capture.frames.pop_front();
+        }
+        let kept = &data[..data.len().min(CAPTURE_SIZE)];
+        let _ = capture.frames.push_back(Frame {
+            direction,
+            gap,
+            len: data.len(),
+            data: Vec::from_slice(kept).unwrap_or_default(),
+        });
+    });
+
+    RECORDED.signal(());
+}
+
+/// Wait until a frame is recorded on any port. Only one task may wait at a time.
+pub(crate) async fn recorded() {
+    RECORDED.wait().await
+}
+
+/// Everything on the monitor screen.
+#[derive(Clone)]
+pub(crate) struct Lines {
+    pub(crate) title: Line,
+    pub(crate) lines: Vec<Line, { ROWS - 1 }>,
+}
+
+/// The port shown on the monitor screen, and the frames it is held at while paused.
+pub(crate) struct View {
+    port: usize,
+    paused: Option<Frames>,
+}
+
+impl View {
+    pub(crate) const fn new() -> Self {
+        Self {
+            port: 0,
+            paused: None,
+        }
+    }
+
+    /// Show the next port, carrying on from where it is now.
+    pub(crate) fn next_port(&mut self) {
+        self.port = (self.port + 1) % PORT_COUNT;
+        self.paused = None;
+    }
+
+    pub(crate) fn toggle_pause(&mut self) {
+        self.paused = match self.paused {
+            Some(_) => None,
+            None => Some(CAPTURES[self.port].lock(|c| c.borrow().frames.clone())),
+        };
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// The lines to show, scrolled so the newest frame is at the bottom.
+    pub(crate) fn lines(&self) -> Lines {
+        let frames = match &self.paused {
+            Some(frames) => frames.clone(),
+            None => CAPTURES[self.port].lock(|c| c.borrow().frames.clone()),
+        };
+
+        let mut title = Line::new();
+        let state = if self.is_paused() {
+            "paused"
+        } else {
+            "monitor"
+        };
+        let _ = write!(title, "Port {} {}", self.port, state);
+
+        let mut lines = Deque::<Line, { ROWS - 1 }>::new();
+        let mut add = |line: Line| {
+            if lines.is_full() {
+                lines.pop_front();
+            }
+            let _ = lines.push_back(line);
+        };
+
+        if frames.is_empty() {
+            add(Line::try_from("No traffic yet").unwrap_or_default());
+        }
+
+        for frame in frames.iter() {
+            let (marker, name) = match frame.direction {
+                Direction::Rx => ('<', "RX"),
+                Direction::Tx => ('>', "TX"),
+            };
+
+            let mut header = Line::new();
+            let _ = write!(header, "{marker} {name}");
+            if let Some(gap) = frame.gap {
+                let ms = gap.as_millis();
+                let _ = write!(header, " +{}.{:03}s", ms / 1000, ms % 1000);
+            }
+            let _ = write!(header, " {} B", frame.len);
+            add(header);
+
+            for chunk in frame.data.chunks(BYTES_PER_LINE) {
+                let mut line = Line::new();
+                let _ = line.push_str("  ");
+                for byte in chunk {
+                    let _ = write!(line, "{byte:02X} ");
+                }
+                // Line the ASCII up under that of full lines
+                let padding = 3 * (BYTES_PER_LINE - chunk.len());
+                let _ = write!(line, "{:padding$}", "");
+                for byte in chunk {
+                    let c = if byte.is_ascii_graphic() || *byte == b' ' {
+                        *byte as char
+                    } else {
+                        '.'
+                    };
+                    let _ = line.push(c);
+                }
+                add(line);
+            }
+
+            if frame.len > frame.data.len() {
+                add(Line::try_from("  ...").unwrap_or_default());
+            }
+        }
+
+        Lines {
+            title,
+            lines: lines.iter().cloned().collect(),
+        }
+    }
+}
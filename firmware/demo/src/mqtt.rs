@@ -0,0 +1,444 @@
+//! MQTT 3.1.1 client publishing data received on the RS485 ports and sending data published to
+//! the board on the line.
+//!
+//! Topics are under `pi485/<id>/`, where `<id>` is the unique ID in hex:
+//! - `port<N>/rx`: data received on port N, one message per chunk or frame
+//! - `port<N>/tx`: data to send on port N
+//! - `status`: retained `online` while connected, the will sets it to `offline` otherwise
+//! - `poll<I>/<J>`: retained value J of the poll at index I in the poll list, as decimal text
+//!
+//! Everything is published and subscribed at QoS 0.
+
+use crate::{
+    config::{self, MqttBroker},
+    identity, poller,
+    rs485::{PORTS, PORT_COUNT},
+    stats,
+    syslog::{self, Severity},
+};
+use core::{cell::Cell, fmt::Write as _};
+use defmt::{debug, info, warn, Format};
+use embassy_futures::select::{select, select4, select_array, Either, Either4};
+use embassy_net::{
+    tcp::{TcpReader, TcpSocket},
+    IpEndpoint, Stack,
+};
+use embassy_sync::{
+    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
+    mutex::Mutex,
+    pubsub::WaitResult,
+    signal::Signal,
+};
+use embassy_time::{with_timeout, Duration, Instant, TimeoutError, Timer};
+use embedded_io_async::{Read, ReadExactError, Write};
+use heapless::{String, Vec};
+
+pub(crate) const DEFAULT_PORT: u16 = 1883;
+
+const KEEP_ALIVE: Duration = Duration::from_secs(60);
+/// Time allowed for the broker to accept the connection.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Largest packet sent or received, larger incoming packets are dropped.
+const MAX_PACKET_SIZE: usize = 512;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+
+/// Set in the first byte of a PUBLISH packet for the broker to keep the message for subscribers
+/// that arrive later.
+const RETAIN: u8 = 0x01;
+
+const CONNECT_CLEAN_SESSION: u8 = 0x02;
+const CONNECT_WILL: u8 = 0x04;
+const CONNECT_WILL_RETAIN: u8 = 0x20;
+
+/// Signalled when the broker setting changes, so the client doesn't wait out its backoff.
+static BROKER_CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+type Packet = Vec<u8, MAX_PACKET_SIZE>;
+type Topic = String<48>;
+
+#[derive(Format)]
+enum Error {
+    Network,
+    /// The broker refused the connection, with the return code from CONNACK.
+    Refused(u8),
+    /// The broker sent something other than what was expected.
+    Protocol,
+    /// The broker stopped responding.
+    Timeout,
+}
+
+impl From<embassy_net::tcp::Error> for Error {
+    fn from(_: embassy_net::tcp::Error) -> Self {
+        Error::Network
+    }
+}
+
+impl From<ReadExactError<embassy_net::tcp::Error>> for Error {
+    fn from(_: ReadExactError<embassy_net::tcp::Error>) -> Self {
+        Error::Network
+    }
+}
+
+impl From<TimeoutError> for Error {
+    fn from(_: TimeoutError) -> Self {
+        Error::Timeout
+    }
+}
+
+struct Topics {
+    /// Prefix of all the topics.
+    base: Topic,
+    status: Topic,
+    rx: [Topic; PORT_COUNT],
+    tx: [Topic; PORT_COUNT],
+}
+
+impl Topics {
+    fn new() -> Self {
+        let id = identity::id();
+
+        let topic = |args: core::fmt::Arguments| {
+            let mut topic = Topic::new();
+            let _ = topic.write_fmt(args);
+            topic
+        };
+
+        Self {
+            base: topic(format_args!("pi485/{id}")),
+            status: topic(format_args!("pi485/{id}/status")),
+            rx: core::array::from_fn(|n| topic(format_args!("pi485/{id}/port{n}/rx"))),
+            tx: core::array::from_fn(|n| topic(format_args!("pi485/{id}/port{n}/tx"))),
+        }
+    }
+}
+
+/// Drop any connection and connect to the broker now in the settings, if there is one.
+pub(crate) fn broker_changed() {
+    BROKER_CHANGED.signal(());
+}
+
+#[embassy_executor::task]
+pub(super) async fn task(stack: Stack<'static>) {
+    let topics = Topics::new();
+
+    let mut rx_buffer = [0; 1024];
+    let mut tx_buffer = [0; 1024];
+    let mut backoff = MIN_BACKOFF;
+
+    loop {
+        let Some(broker) = config::get().mqtt_broker else {
+            BROKER_CHANGED.wait().await;
+            continue;
+        };
+
+        stack.wait_config_up().await;
+
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(KEEP_ALIVE));
+
+        let res = select(
+            run(stack, &mut socket, broker, &topics, &mut backoff),
+            BROKER_CHANGED.wait(),
+        )
+        .await;
+
+        socket.abort();
+        let _ = socket.flush().await;
+
+        if let Either::First(Err(e)) = &res {
+            warn!(
+                "MQTT disconnected: {}, retrying in {} s",
+                e,
+                backoff.as_secs()
+            );
+            syslog::log(
+                Severity::Warning,
+                format_args!("MQTT disconnected, retrying in {} s", backoff.as_secs()),
+            );
+        }
+
+        let changed = match res {
+            Either::First(_) => matches!(
+                select(Timer::after(backoff), BROKER_CHANGED.wait()).await,
+                Either::Second(_)
+            ),
+            Either::Second(_) => true,
+        };
+
+        if changed {
+            info!("MQTT broker changed");
+            backoff = MIN_BACKOFF;
+        } else {
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+async fn run(
+    stack: Stack<'static>,
+    socket: &mut TcpSocket<'_>,
+    broker: MqttBroker,
+    topics: &Topics,
+    backoff: &mut Duration,
+) -> Result<(), Error> {
+    let endpoint = IpEndpoint::new(broker.address.into(), broker.port);
+    info!("MQTT connecting to {}", endpoint);
+
+    if let Err(e) = socket.connect(endpoint).await {
+        warn!("MQTT connect failed: {}", e);
+        return Err(Error::Network);
+    }
+
+    // The connection cannot survive the link going down, or the board moving network
+    match select(session(socket, topics, backoff), stack.wait_link_down()).await {
+        Either::First(res) => res,
+        Either::Second(_) => Err(Error::Network),
+    }
+}
+
+async fn session(
+    socket: &mut TcpSocket<'_>,
+    topics: &Topics,
+    backoff: &mut Duration,
+) -> Result<(), Error> {
+    let (mut reader, mut writer) = socket.split();
+    let mut buf = [0; MAX_PACKET_SIZE];
+
+    writer
+        .write_all(&connect(&identity::hostname(), &topics.status))
+        .await?;
+
+    match with_timeout(CONNECT_TIMEOUT, read_packet(&mut reader, &mut buf)).await?? {
+        (CONNACK, Some(&[_, 0])) => {}
+        (CONNACK, Some(&[_, code])) => return Err(Error::Refused(code)),
+        _ => return Err(Error::Protocol),
+    }
+
+    info!("MQTT connected");
+    syslog::log(Severity::Informational, format_args!("MQTT connected"));
+    *backoff = MIN_BACKOFF;
+
+    writer.write_all(&subscribe(&topics.tx)).await?;
+    writer
+        .write_all(&publish(&topics.status, b"online", true))
+        .await?;
+
+    let writer = Mutex::<NoopRawMutex, _>::new(writer);
+    let last_received = Cell::new(Instant::now());
+
+    let incoming = async {
+        loop {
+            let (header, body) = read_packet(&mut reader, &mut buf).await?;
+            last_received.set(Instant::now());
+
+            match (header & 0xf0, body) {
+                (PUBLISH, Some(body)) => receive(header, body, topics).await?,
+                (PUBLISH, None) => warn!("MQTT message too large, dropped"),
+                (SUBACK, _) => debug!("MQTT subscribed"),
+                (PINGRESP, _) => {}
+                _ => return Err(Error::Protocol),
+            }
+        }
+    };
+
+    let from_line = async {
+        let mut subscribers = PORTS.each_ref().map(|port| port.subscribe());
+        for (n, subscriber) in subscribers.iter().enumerate() {
+            if subscriber.is_none() {
+                warn!("MQTT has no free receivers on port {}", n);
+            }
+        }
+
+        loop {
+            let (message, n) = select_array(subscribers.each_mut().map(|subscriber| async move {
+                match subscriber {
+                    Some(subscriber) => subscriber.next_message().await,
+                    None => core::future::pending().await,
+                }
+            }))
+            .await;
+
+            match message {
+                WaitResult::Lagged(count) => {
+                    warn!("MQTT lagged on port {}, {} chunks lost", n, count);
+                    stats::PORTS[n].record_dropped(count);
+                }
+                WaitResult::Message(data) => {
+                    let packet = publish(&topics.rx[n], &data, false);
+                    writer.lock().await.write_all(&packet).await?;
+                }
+            }
+        }
+    };
+
+    let keep_alive = async {
+        loop {
+            Timer::after(KEEP_ALIVE / 2).await;
+
+            // Nothing at all since the previous ping, not even its response
+            if last_received.get().elapsed() > KEEP_ALIVE {
+                return Err(Error::Timeout);
+            }
+
+            writer.lock().await.write_all(&[PINGREQ, 0]).await?;
+        }
+    };
+
+    let poll_values = async {
+        loop {
+            let i = poller::UPDATES.receive().await;
+
+            for (j, value) in poller::status(i).values.iter().enumerate() {
+                let mut topic = Topic::new();
+                let _ = write!(topic, "{}/poll{i}/{j}", topics.base);
+                let mut payload = String::<24>::new();
+                let _ = write!(payload, "{value}");
+
+                let packet = publish(&topic, payload.as_bytes(), true);
+                writer.lock().await.write_all(&packet).await?;
+            }
+        }
+    };
+
+    match select4(incoming, from_line, keep_alive, poll_values).await {
+        Either4::First(res) | Either4::Second(res) | Either4::Third(res) | Either4::Fourth(res) => {
+            res
+        }
+    }
+}
+
+/// Send a message published to one of the transmit topics on the line.
+async fn receive(header: u8, body: &[u8], topics: &Topics) -> Result<(), Error> {
+    let [l0, l1, rest @ ..] = body else {
+        return Err(Error::Protocol);
+    };
+    let (topic, rest) = rest
+        .split_at_checked(u16::from_be_bytes([*l0, *l1]) as usize)
+        .ok_or(Error::Protocol)?;
+
+    // Only QoS 0 is subscribed to, but skip the packet identifier should a broker send one anyway
+    let payload = match (header >> 1) & 0x03 {
+        0 => rest,
+        _ => rest.get(2..).ok_or(Error::Protocol)?,
+    };
+
+    match topics.tx.iter().position(|t| t.as_bytes() == topic) {
+        Some(n) => {
+            debug!("MQTT->RS485 {}: {:x}", n, payload);
+            PORTS[n].write(payload).await;
+        }
+        None => warn!("MQTT message on unexpected topic"),
+    }
+
+    Ok(())
+}
+
+/// Read a packet, returning its first byte and its contents following the fixed header. Packets
+/// too large for `buf` are skipped and `None` returned in place of the contents.
+async fn read_packet<'a>(
+    reader: &mut TcpReader<'_>,
+    buf: &'a mut [u8],
+) -> Result<(u8, Option<&'a [u8]>), Error> {
+    let mut byte = [0];
+    reader.read_exact(&mut byte).await?;
+    let header = byte[0];
+
+    // Remaining length, seven bits at a time in up to four bytes
+    let mut len = 0;
+    for i in 0.. {
+        if i == 4 {
+            return Err(Error::Protocol);
+        }
+        reader.read_exact(&mut byte).await?;
+        len |= ((byte[0] & 0x7f) as usize) << (7 * i);
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+
+    if len > buf.len() {
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = remaining.min(buf.len());
+            reader.read_exact(&mut buf[..chunk]).await?;
+            remaining -= chunk;
+        }
+        return Ok((header, None));
+    }
+
+    reader.read_exact(&mut buf[..len]).await?;
+    Ok((header, Some(&buf[..len])))
+}
+
+/// Build a packet from its first byte and the contents written by `contents`.
+fn packet(header: u8, contents: impl FnOnce(&mut Packet)) -> Packet {
+    let mut body = Packet::new();
+    contents(&mut body);
+
+    let mut packet = Packet::new();
+    let _ = packet.push(header);
+
+    let mut len = body.len();
+    loop {
+        let byte = (len & 0x7f) as u8;
+        len >>= 7;
+        if len == 0 {
+            let _ = packet.push(byte);
+            break;
+        }
+        let _ = packet.push(byte | 0x80);
+    }
+
+    let _ = packet.extend_from_slice(&body);
+    packet
+}
+
+/// Write a string or binary data prefixed with its length.
+fn put_bytes(packet: &mut Packet, data: &[u8]) {
+    let _ = packet.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    let _ = packet.extend_from_slice(data);
+}
+
+fn connect(client_id: &str, will_topic: &str) -> Packet {
+    packet(CONNECT, |p| {
+        put_bytes(p, b"MQTT");
+        // Protocol level of MQTT 3.1.1
+        let _ = p.push(4);
+        let _ = p.push(CONNECT_CLEAN_SESSION | CONNECT_WILL | CONNECT_WILL_RETAIN);
+        let _ = p.extend_from_slice(&(KEEP_ALIVE.as_secs() as u16).to_be_bytes());
+        put_bytes(p, client_id.as_bytes());
+        put_bytes(p, will_topic.as_bytes());
+        put_bytes(p, b"offline");
+    })
+}
+
+fn subscribe(topics: &[Topic]) -> Packet {
+    packet(SUBSCRIBE, |p| {
+        // Packet identifier, there is only ever one subscription outstanding
+        let _ = p.extend_from_slice(&1u16.to_be_bytes());
+        for topic in topics {
+            put_bytes(p, topic.as_bytes());
+            // QoS 0
+            let _ = p.push(0);
+        }
+    })
+}
+
+fn publish(topic: &str, payload: &[u8], retain: bool) -> Packet {
+    let header = if retain { PUBLISH | RETAIN } else { PUBLISH };
+    packet(header, |p| {
+        put_bytes(p, topic.as_bytes());
+        let _ = p.extend_from_slice(payload);
+    })
+}
@@ -0,0 +1,286 @@
+//! Modbus RTU master polling registers of devices on the RS485 ports on a schedule.
+//!
+//! The latest values of each poll are kept in a table other modules can read, and the index of a
+//! poll is sent on [`UPDATES`] each time its values are refreshed.
+
+use crate::{
+    config,
+    modbus::{self, Pdu},
+    rs485::{PORTS, PORT_COUNT},
+};
+use core::cell::RefCell;
+use defmt::{debug, info, warn, Format};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    channel::Channel,
+};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+/// Number of polls that can be configured.
+pub(crate) const MAX_POLLS: usize = 8;
+
+/// Most registers a single poll can read.
+pub(crate) const MAX_REGISTERS: usize = 32;
+
+/// How often to look for changes to the configured polls while waiting for the next one.
+const CONFIG_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+const READ_HOLDING_REGISTERS: u8 = 0x03;
+const READ_INPUT_REGISTERS: u8 = 0x04;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum RegisterType {
+    Holding,
+    Input,
+}
+
+impl RegisterType {
+    pub(crate) const ALL: [RegisterType; 2] = [RegisterType::Holding, RegisterType::Input];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            RegisterType::Holding => "holding",
+            RegisterType::Input => "input",
+        }
+    }
+
+    pub(crate) fn function(self) -> u8 {
+        match self {
+            RegisterType::Holding => READ_HOLDING_REGISTERS,
+            RegisterType::Input => READ_INPUT_REGISTERS,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum DataType {
+    U16,
+    I16,
+    U32,
+    F32,
+}
+
+impl DataType {
+    pub(crate) const ALL: [DataType; 4] =
+        [DataType::U16, DataType::I16, DataType::U32, DataType::F32];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            DataType::U16 => "u16",
+            DataType::I16 => "i16",
+            DataType::U32 => "u32",
+            DataType::F32 => "f32",
+        }
+    }
+
+    /// Number of registers taken by each value.
+    pub(crate) fn registers(self) -> usize {
+        match self {
+            DataType::U16 | DataType::I16 => 1,
+            DataType::U32 | DataType::F32 => 2,
+        }
+    }
+}
+
+/// Order of the registers making up a 32 bit value, the bytes within each register are always big
+/// endian.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum WordOrder {
+    HighFirst,
+    LowFirst,
+}
+
+impl WordOrder {
+    pub(crate) const ALL: [WordOrder; 2] = [WordOrder::HighFirst, WordOrder::LowFirst];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            WordOrder::HighFirst => "high_first",
+            WordOrder::LowFirst => "low_first",
+        }
+    }
+}
+
+/// A block of registers read from a device at a fixed interval.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Poll {
+    pub(crate) port: usize,
+    pub(crate) address: u8,
+    pub(crate) register_type: RegisterType,
+    pub(crate) start: u16,
+    /// Number of registers, a multiple of the size of `data_type`.
+    pub(crate) count: u8,
+    pub(crate) interval: Duration,
+    pub(crate) data_type: DataType,
+    pub(crate) word_order: WordOrder,
+    /// Values are multiplied by ten to the power of this.
+    pub(crate) scale: i8,
+}
+
+impl Poll {
+    /// Returns false if the poll cannot be carried out as described.
+    pub(crate) fn is_valid(&self) -> bool {
+        let count = self.count as usize;
+        self.port < PORT_COUNT
+            && (1..=247).contains(&self.address)
+            && count > 0
+            && count <= MAX_REGISTERS
+            && count % self.data_type.registers() == 0
+            && self.interval > Duration::from_ticks(0)
+    }
+}
+
+/// What is known of the device answering a poll.
+#[derive(Clone, Default)]
+pub(crate) struct Status {
+    /// Values from the most recent successful poll, with the scale applied.
+    pub(crate) values: Vec<f32, MAX_REGISTERS>,
+    /// When the values were read, `None` if they never have been.
+    pub(crate) updated: Option<Instant>,
+    /// Number of polls that failed.
+    pub(crate) failures: u32,
+}
+
+impl Status {
+    const fn new() -> Self {
+        Self {
+            values: Vec::new(),
+            updated: None,
+            failures: 0,
+        }
+    }
+}
+
+static STATUS: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<[Status; MAX_POLLS]>> =
+    blocking_mutex::Mutex::new(RefCell::new([const { Status::new() }; MAX_POLLS]));
+
+/// Indices of polls whose values have been refreshed. Updates are dropped while it is full.
+pub(crate) static UPDATES: Channel<CriticalSectionRawMutex, usize, MAX_POLLS> = Channel::new();
+
+/// The status of the poll at `index` in the configured list.
+pub(crate) fn status(index: usize) -> Status {
+    STATUS.lock(|s| s.borrow().get(index).cloned().unwrap_or_default())
+}
+
+#[derive(Format)]
+enum Error {
+    Modbus(modbus::Error),
+    /// The device responded with an exception code.
+    Exception(u8),
+    /// The response did not match the request.
+    InvalidResponse,
+}
+
+impl From<modbus::Error> for Error {
+    fn from(e: modbus::Error) -> Self {
+        Error::Modbus(e)
+    }
+}
+
+#[embassy_executor::task]
+pub(super) async fn task() {
+    let mut polls = [None; MAX_POLLS];
+    let mut next = [Instant::now(); MAX_POLLS];
+
+    loop {
+        let configured = config::get().polls;
+        if configured != polls {
+            info!("Poll list changed");
+            polls = configured;
+            next = [Instant::now(); MAX_POLLS];
+            STATUS.lock(|s| *s.borrow_mut() = [const { Status::new() }; MAX_POLLS]);
+        }
+
+        let due = polls
+            .iter()
+            .zip(next)
+            .enumerate()
+            .filter_map(|(i, (p, at))| p.map(|p| (i, p, at)))
+            .min_by_key(|(_, _, at)| *at);
+
+        let now = Instant::now();
+
+        match due {
+            Some((i, p, at)) if at <= now => {
+                // Start again from now if polling has fallen behind, rather than catching up
+                next[i] = (at + p.interval).max(now);
+
+                match poll(&p).await {
+                    Ok(values) => {
+                        debug!("Poll {}: {}", i, values.as_slice());
+                        STATUS.lock(|s| {
+                            let status = &mut s.borrow_mut()[i];
+                            status.values = values;
+                            status.updated = Some(Instant::now());
+                        });
+                        let _ = UPDATES.try_send(i);
+                    }
+                    Err(e) => {
+                        warn!("Poll {} of device {} failed: {}", i, p.address, e);
+                        STATUS.lock(|s| s.borrow_mut()[i].failures += 1);
+                    }
+                }
+            }
+            Some((_, _, at)) => Timer::at(at.min(now + CONFIG_CHECK_INTERVAL)).await,
+            None => Timer::after(CONFIG_CHECK_INTERVAL).await,
+        }
+    }
+}
+
+async fn poll(poll: &Poll) -> Result<Vec<f32, MAX_REGISTERS>, Error> {
+    let port = &PORTS[poll.port];
+    let function = poll.register_type.function();
+
+    let start = poll.start.to_be_bytes();
+    let request = [function, start[0], start[1], 0, poll.count];
+
+    let response: Pdu = {
+        let bus = port.lock_bus().await;
+        modbus::transact(&bus, poll.address, &request).await?
+    };
+
+    match response.as_slice() {
+        [f, len, data @ ..]
+            if *f == function
+                && *len as usize == data.len()
+                && data.len() == 2 * poll.count as usize =>
+        {
+            Ok(decode(poll, data))
+        }
+        [f, code] if *f == function | 0x80 => Err(Error::Exception(*code)),
+        _ => Err(Error::InvalidResponse),
+    }
+}
+
+/// Convert register data to values, applying the scale.
+fn decode(poll: &Poll, data: &[u8]) -> Vec<f32, MAX_REGISTERS> {
+    let mut scale = 1.0f32;
+    for _ in 0..poll.scale.unsigned_abs() {
+        scale *= 10.0;
+    }
+    if poll.scale < 0 {
+        scale = 1.0 / scale;
+    }
+
+    data.chunks_exact(2 * poll.data_type.registers())
+        .map(|chunk| {
+            let value = match (poll.data_type, chunk) {
+                (DataType::U16, &[b0, b1]) => u16::from_be_bytes([b0, b1]) as f32,
+                (DataType::I16, &[b0, b1]) => i16::from_be_bytes([b0, b1]) as f32,
+                (data_type, &[b0, b1, b2, b3]) => {
+                    let bytes = match poll.word_order {
+                        WordOrder::HighFirst => [b0, b1, b2, b3],
+                        WordOrder::LowFirst => [b2, b3, b0, b1],
+                    };
+                    match data_type {
+                        DataType::F32 => f32::from_be_bytes(bytes),
+                        _ => u32::from_be_bytes(bytes) as f32,
+                    }
+                }
+                _ => f32::NAN,
+            };
+            value * scale
+        })
+        .collect()
+}
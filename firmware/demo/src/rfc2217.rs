@@ -0,0 +1,327 @@
+//! Telnet with the RFC 2217 COM port control option.
+
+use crate::rs485::Port;
+use defmt::{debug, info};
+use embassy_rp::uart::{DataBits, Parity, StopBits};
+use embassy_time::Instant;
+use heapless::Vec;
+
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const OPTION_BINARY: u8 = 0;
+const OPTION_SGA: u8 = 3;
+const OPTION_COM_PORT: u8 = 44;
+
+const SIGNATURE: u8 = 0;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const SET_LINESTATE_MASK: u8 = 10;
+const SET_MODEMSTATE_MASK: u8 = 11;
+const PURGE_DATA: u8 = 12;
+
+/// Offset added to a command code in the server's reply.
+const SERVER_REPLY: u8 = 100;
+
+const CONTROL_FLOW_REQUEST: u8 = 0;
+const CONTROL_FLOW_NONE: u8 = 1;
+const CONTROL_BREAK_REQUEST: u8 = 4;
+const CONTROL_BREAK_ON: u8 = 5;
+const CONTROL_BREAK_OFF: u8 = 6;
+const CONTROL_INBOUND_FLOW_REQUEST: u8 = 13;
+const CONTROL_INBOUND_FLOW_NONE: u8 = 14;
+const CONTROL_DCD_FLOW: u8 = 17;
+const CONTROL_DSR_FLOW: u8 = 19;
+
+const SIGNATURE_TEXT: &[u8] = b"pi485";
+
+/// Options negotiated when a client connects.
+pub(crate) const GREETING: &[u8] = &[
+    IAC,
+    WILL,
+    OPTION_BINARY,
+    IAC,
+    DO,
+    OPTION_BINARY,
+    IAC,
+    WILL,
+    OPTION_SGA,
+    IAC,
+    DO,
+    OPTION_SGA,
+    IAC,
+    DO,
+    OPTION_COM_PORT,
+];
+
+/// Most data passed to [`Session::receive`] at once.
+pub(crate) const RECEIVE_SIZE: usize = 64;
+
+/// Longest reply to one command, a 4 byte value with every byte escaped.
+const MAX_COMMAND_REPLY: usize = 6 + 2 * 4;
+/// Shortest subnegotiation, which may get a reply of [`MAX_COMMAND_REPLY`] bytes. Other commands
+/// get no more back than they take, and one started in earlier data may end in the first byte.
+const MIN_SUBNEGOTIATION: usize = 6;
+
+/// Room for the replies to everything in [`RECEIVE_SIZE`] bytes of client data.
+pub(crate) type Reply =
+    Vec<u8, { MAX_COMMAND_REPLY * (1 + RECEIVE_SIZE.div_ceil(MIN_SUBNEGOTIATION)) }>;
+
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    Iac,
+    Negotiate(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+pub(crate) struct Session {
+    state: State,
+    /// Options enabled on our side, indexed by option bit.
+    local: u8,
+    /// Options enabled on the client's side, indexed by option bit.
+    remote: u8,
+    subnegotiation: Vec<u8, 16>,
+    break_start: Option<Instant>,
+}
+
+impl Session {
+    pub(crate) fn new() -> Self {
+        // Everything in the greeting is considered enabled until the client says otherwise
+        Self {
+            state: State::Data,
+            local: option_bit(OPTION_BINARY) | option_bit(OPTION_SGA),
+            remote: option_bit(OPTION_BINARY)
+                | option_bit(OPTION_SGA)
+                | option_bit(OPTION_COM_PORT),
+            subnegotiation: Vec::new(),
+            break_start: None,
+        }
+    }
+
+    /// Process data from the client, forwarding line data to the port and collecting Telnet replies.
+    pub(crate) async fn receive(&mut self, port: &Port, data: &[u8], reply: &mut Reply) {
+        let mut line = Vec::<u8, 64>::new();
+
+        for &byte in data {
+            let (state, data) = match (self.state, byte) {
+                (State::Data, IAC) => (State::Iac, None),
+                // Either plain data or an escaped 0xFF data byte
+                (State::Data, _) | (State::Iac, IAC) => (State::Data, Some(byte)),
+                (State::Iac, DO | DONT | WILL | WONT) => (State::Negotiate(byte), None),
+                (State::Iac, SB) => {
+                    self.subnegotiation.clear();
+                    (State::Subnegotiation, None)
+                }
+                (State::Iac, _) => (State::Data, None),
+                (State::Negotiate(command), option) => {
+                    self.negotiate(command, option, reply);
+                    (State::Data, None)
+                }
+                (State::Subnegotiation, IAC) => (State::SubnegotiationIac, None),
+                (State::SubnegotiationIac, SE) => {
+                    // Line settings apply to data after the command, not before it
+                    if !line.is_empty() {
+                        port.write(&line).await;
+                        line.clear();
+                    }
+                    self.subnegotiate(port, reply).await;
+                    (State::Data, None)
+                }
+                // Either a parameter byte or an escaped 0xFF parameter byte
+                (State::Subnegotiation, _) | (State::SubnegotiationIac, _) => {
+                    let _ = self.subnegotiation.push(byte);
+                    (State::Subnegotiation, None)
+                }
+            };
+
+            self.state = state;
+
+            if let Some(byte) = data {
+                if line.is_full() {
+                    port.write(&line).await;
+                    line.clear();
+                }
+                let _ = line.push(byte);
+            }
+        }
+
+        if !line.is_empty() {
+            port.write(&line).await;
+        }
+    }
+
+    fn negotiate(&mut self, command: u8, option: u8, reply: &mut Reply) {
+        let bit = option_bit(option);
+
+        let response = match command {
+            DO if bit & (option_bit(OPTION_BINARY) | option_bit(OPTION_SGA)) != 0 => {
+                (self.local & bit == 0).then_some(WILL)
+            }
+            DO => Some(WONT),
+            DONT => (self.local & bit != 0).then_some(WONT),
+            WILL if bit != 0 => (self.remote & bit == 0).then_some(DO),
+            WILL => Some(DONT),
+            WONT => (self.remote & bit != 0).then_some(DONT),
+            _ => None,
+        };
+
+        match response {
+            Some(WILL) => self.local |= bit,
+            Some(WONT) => self.local &= !bit,
+            Some(DO) => self.remote |= bit,
+            Some(DONT) => self.remote &= !bit,
+            _ => {}
+        }
+
+        if let Some(response) = response {
+            let _ = reply.extend_from_slice(&[IAC, response, option]);
+        }
+    }
+
+    async fn subnegotiate(&mut self, port: &Port, reply: &mut Reply) {
+        let Some((&OPTION_COM_PORT, request)) = self.subnegotiation.split_first() else {
+            return;
+        };
+        let Some((&command, value)) = request.split_first() else {
+            return;
+        };
+
+        let mut config = port.config();
+
+        let mut response = Vec::<u8, 8>::new();
+
+        match command {
+            SIGNATURE => {
+                if value.is_empty() {
+                    write_subnegotiation(reply, SIGNATURE + SERVER_REPLY, SIGNATURE_TEXT);
+                } else {
+                    info!("RFC 2217 client: {=[u8]:a}", value);
+                }
+                return;
+            }
+            SET_BAUDRATE => {
+                if let Ok(value) = value.try_into() {
+                    let baudrate = u32::from_be_bytes(value);
+                    if baudrate != 0 {
+                        config.baudrate = baudrate;
+                    }
+                }
+                let _ = response.extend_from_slice(&config.baudrate.to_be_bytes());
+            }
+            SET_DATASIZE => {
+                config.data_bits = match value.first() {
+                    Some(5) => DataBits::DataBits5,
+                    Some(6) => DataBits::DataBits6,
+                    Some(7) => DataBits::DataBits7,
+                    Some(8) => DataBits::DataBits8,
+                    _ => config.data_bits,
+                };
+                let _ = response.push(match config.data_bits {
+                    DataBits::DataBits5 => 5,
+                    DataBits::DataBits6 => 6,
+                    DataBits::DataBits7 => 7,
+                    DataBits::DataBits8 => 8,
+                });
+            }
+            SET_PARITY => {
+                config.parity = match value.first() {
+                    Some(1) => Parity::ParityNone,
+                    Some(2) => Parity::ParityOdd,
+                    Some(3) => Parity::ParityEven,
+                    _ => config.parity,
+                };
+                let _ = response.push(match config.parity {
+                    Parity::ParityNone => 1,
+                    Parity::ParityOdd => 2,
+                    Parity::ParityEven => 3,
+                });
+            }
+            SET_STOPSIZE => {
+                config.stop_bits = match value.first() {
+                    Some(1) => StopBits::STOP1,
+                    Some(2) => StopBits::STOP2,
+                    _ => config.stop_bits,
+                };
+                let _ = response.push(match config.stop_bits {
+                    StopBits::STOP1 => 1,
+                    StopBits::STOP2 => 2,
+                });
+            }
+            SET_CONTROL => {
+                let value = value.first().copied().unwrap_or(CONTROL_FLOW_REQUEST);
+                let value = match value {
+                    CONTROL_BREAK_ON => {
+                        self.break_start = Some(Instant::now());
+                        value
+                    }
+                    CONTROL_BREAK_OFF => {
+                        if let Some(start) = self.break_start.take() {
+                            port.send_break(start.elapsed()).await;
+                        }
+                        value
+                    }
+                    CONTROL_BREAK_REQUEST => match self.break_start {
+                        Some(_) => CONTROL_BREAK_ON,
+                        None => CONTROL_BREAK_OFF,
+                    },
+                    // There is no flow control on an RS485 line, in either direction
+                    0..=3 | CONTROL_DCD_FLOW | CONTROL_DSR_FLOW => CONTROL_FLOW_NONE,
+                    CONTROL_INBOUND_FLOW_REQUEST..=16 | 18 => CONTROL_INBOUND_FLOW_NONE,
+                    // DTR and RTS do not exist either, acknowledge whatever is asked for
+                    _ => value,
+                };
+                let _ = response.push(value);
+            }
+            SET_LINESTATE_MASK | SET_MODEMSTATE_MASK | PURGE_DATA => {
+                // Each takes a single byte
+                let _ = response.extend_from_slice(&value[..value.len().min(1)]);
+            }
+            _ => {
+                debug!("Unsupported RFC 2217 command {}", command);
+                return;
+            }
+        }
+
+        if config != port.config() {
+            info!("RFC 2217 changing line settings");
+            port.set_config(config);
+        }
+
+        write_subnegotiation(reply, command + SERVER_REPLY, &response);
+    }
+}
+
+fn option_bit(option: u8) -> u8 {
+    match option {
+        OPTION_BINARY => 1 << 0,
+        OPTION_SGA => 1 << 1,
+        OPTION_COM_PORT => 1 << 2,
+        _ => 0,
+    }
+}
+
+fn write_subnegotiation(reply: &mut Reply, command: u8, value: &[u8]) {
+    let _ = reply.extend_from_slice(&[IAC, SB, OPTION_COM_PORT, command]);
+    escape(value, reply);
+    let _ = reply.extend_from_slice(&[IAC, SE]);
+}
+
+/// Append data to a buffer, doubling any IAC bytes.
+pub(crate) fn escape<const N: usize>(data: &[u8], out: &mut Vec<u8, N>) {
+    for &byte in data {
+        if byte == IAC {
+            let _ = out.push(IAC);
+        }
+        let _ = out.push(byte);
+    }
+}
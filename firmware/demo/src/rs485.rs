@@ -1,13 +1,38 @@
-use crate::{Rs485Uart0Resources, Rs485Uart1Resources};
-use defmt::info;
-use embassy_futures::select::{select3, Either3};
+use crate::{
+    config,
+    framing::{self, Framing},
+    modbus,
+    monitor::{self, Direction},
+    stats, status,
+    syslog::{self, Severity},
+    Rs485Uart0Resources, Rs485Uart1Resources,
+};
+use core::cell::Cell;
+use defmt::{debug, info, warn};
+use embassy_futures::{
+    join::{join, join3},
+    select::{select, Either},
+};
 use embassy_rp::{
     bind_interrupts,
+    interrupt::typelevel::Binding,
     peripherals::{UART0, UART1},
-    uart::{BufferedInterruptHandler, BufferedUart, Config},
+    uart::{
+        BufferedInterruptHandler, BufferedUart, Config, DataBits, Instance, Parity, RxPin,
+        StopBits, TxPin,
+    },
+    Peri,
+};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    channel::Channel,
+    mutex::{Mutex, MutexGuard},
+    pubsub::{PubSubChannel, Subscriber},
+    signal::Signal,
 };
-use embassy_time::{Duration, Ticker};
+use embassy_time::{Duration, Timer};
 use embedded_io_async::{Read, Write};
+use heapless::Vec;
 use static_cell::StaticCell;
 
 bind_interrupts!(struct IrqsUart0 {
@@ -18,13 +43,218 @@ bind_interrupts!(struct IrqsUart1 {
     UART1_IRQ  => BufferedInterruptHandler<UART1>;
 });
 
+pub(crate) const PORT_COUNT: usize = 2;
+
+/// Large enough for a whole Modbus RTU frame.
+pub(crate) const PAYLOAD_SIZE: usize = 256;
+
+pub(crate) type Payload = Vec<u8, PAYLOAD_SIZE>;
+
+const RX_SUBSCRIBERS: usize = 9;
+
+/// How often receive errors are summed up for the syslog server, a noisy line would flood it if
+/// each one was sent.
+const ERROR_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+pub(crate) type RxSubscriber<'a> =
+    Subscriber<'a, CriticalSectionRawMutex, Payload, 8, RX_SUBSCRIBERS, 1>;
+
+/// Line settings of an RS485 interface.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LineConfig {
+    pub(crate) baudrate: u32,
+    pub(crate) data_bits: DataBits,
+    pub(crate) parity: Parity,
+    pub(crate) stop_bits: StopBits,
+}
+
+impl LineConfig {
+    pub(crate) const fn new(baudrate: u32) -> Self {
+        Self {
+            baudrate,
+            data_bits: DataBits::DataBits8,
+            parity: Parity::ParityNone,
+            stop_bits: StopBits::STOP1,
+        }
+    }
+
+    pub(crate) fn data_bit_count(&self) -> u8 {
+        match self.data_bits {
+            DataBits::DataBits5 => 5,
+            DataBits::DataBits6 => 6,
+            DataBits::DataBits7 => 7,
+            DataBits::DataBits8 => 8,
+        }
+    }
+
+    pub(crate) fn stop_bit_count(&self) -> u8 {
+        match self.stop_bits {
+            StopBits::STOP1 => 1,
+            StopBits::STOP2 => 2,
+        }
+    }
+
+    /// Time taken to send one character, including start, parity and stop bits.
+    pub(crate) fn char_time(&self) -> Duration {
+        let parity_bits = match self.parity {
+            Parity::ParityNone => 0,
+            Parity::ParityEven | Parity::ParityOdd => 1,
+        };
+        let bits = 1 + self.data_bit_count() as u64 + parity_bits + self.stop_bit_count() as u64;
+        Duration::from_micros(bits * 1_000_000 / self.baudrate as u64)
+    }
+}
+
+impl From<LineConfig> for Config {
+    fn from(line: LineConfig) -> Self {
+        let mut config = Config::default();
+        config.baudrate = line.baudrate;
+        config.data_bits = line.data_bits;
+        config.parity = line.parity;
+        config.stop_bits = line.stop_bits;
+        config
+    }
+}
+
+/// Shared handle to one of the RS485 interfaces.
+///
+/// Data written to the port is queued for transmission, data received on the line is broadcast to
+/// every subscriber.
+pub(crate) struct Port {
+    config: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<LineConfig>>,
+    /// Framing from the settings.
+    framing: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<Framing>>>,
+    /// Framing used instead while the bus is locked for a request/response exchange.
+    bus_framing: blocking_mutex::Mutex<CriticalSectionRawMutex, Cell<Option<Framing>>>,
+    framing_changed: Signal<CriticalSectionRawMutex, ()>,
+    reconfigure: Signal<CriticalSectionRawMutex, ()>,
+    tx: Channel<CriticalSectionRawMutex, Transmit, 8>,
+    rx: PubSubChannel<CriticalSectionRawMutex, Payload, 8, RX_SUBSCRIBERS, 1>,
+    bus: Mutex<CriticalSectionRawMutex, ()>,
+}
+
+#[allow(clippy::large_enum_variant)]
+enum Transmit {
+    Data(Payload),
+    Break(Duration),
+}
+
+impl Port {
+    const fn new(config: LineConfig) -> Self {
+        Self {
+            config: blocking_mutex::Mutex::new(Cell::new(config)),
+            framing: blocking_mutex::Mutex::new(Cell::new(None)),
+            bus_framing: blocking_mutex::Mutex::new(Cell::new(None)),
+            framing_changed: Signal::new(),
+            reconfigure: Signal::new(),
+            tx: Channel::new(),
+            rx: PubSubChannel::new(),
+            bus: Mutex::new(()),
+        }
+    }
+
+    pub(crate) fn config(&self) -> LineConfig {
+        self.config.lock(|c| c.get())
+    }
+
+    /// Change the line settings, the UART is reinitialised if they differ from the current ones.
+    pub(crate) fn set_config(&self, config: LineConfig) {
+        if self.config.lock(|c| c.replace(config)) != config {
+            self.reconfigure.signal(());
+        }
+    }
+
+    pub(crate) fn framing(&self) -> Option<Framing> {
+        self.framing.lock(|c| c.get())
+    }
+
+    /// Group received data into frames, or pass it on as it arrives if `None`.
+    ///
+    /// Applies from the next data received, unless a frame has already been started or the bus is
+    /// locked with framing of its own.
+    pub(crate) fn set_framing(&self, framing: Option<Framing>) {
+        self.change_framing(|| self.framing.lock(|c| c.set(framing)));
+    }
+
+    /// The framing received data is grouped by at the moment.
+    fn rx_framing(&self) -> Option<Framing> {
+        self.bus_framing.lock(|c| c.get()).or(self.framing())
+    }
+
+    fn change_framing(&self, change: impl FnOnce()) {
+        let before = self.rx_framing();
+        change();
+        if self.rx_framing() != before {
+            self.framing_changed.signal(());
+        }
+    }
+
+    /// Queue data for transmission on the line.
+    pub(crate) async fn write(&self, data: &[u8]) {
+        for chunk in data.chunks(PAYLOAD_SIZE) {
+            let mut payload = Payload::new();
+            let _ = payload.extend_from_slice(chunk);
+            self.tx.send(Transmit::Data(payload)).await;
+        }
+    }
+
+    /// Queue a break condition of the given length, after any data already queued.
+    pub(crate) async fn send_break(&self, duration: Duration) {
+        self.tx.send(Transmit::Break(duration)).await;
+    }
+
+    /// Start receiving data from the line, returns `None` if there are too many receivers.
+    pub(crate) fn subscribe(&self) -> Option<RxSubscriber<'_>> {
+        self.rx.subscriber().ok()
+    }
+
+    /// Obtain exclusive use of the bus for a request/response exchange.
+    pub(crate) async fn lock_bus(&self) -> BusGuard<'_> {
+        BusGuard {
+            port: self,
+            _lock: self.bus.lock().await,
+        }
+    }
+}
+
+/// Exclusive use of the bus of a port, released when dropped.
+pub(crate) struct BusGuard<'a> {
+    port: &'a Port,
+    _lock: MutexGuard<'a, CriticalSectionRawMutex, ()>,
+}
+
+impl<'a> BusGuard<'a> {
+    pub(crate) fn port(&self) -> &'a Port {
+        self.port
+    }
+
+    /// Group received data by `framing` instead of the port's own setting until the guard is
+    /// dropped.
+    pub(crate) fn set_framing(&self, framing: Framing) {
+        self.port
+            .change_framing(|| self.port.bus_framing.lock(|c| c.set(Some(framing))));
+    }
+}
+
+impl Drop for BusGuard<'_> {
+    fn drop(&mut self) {
+        // Runs before the lock field is dropped, so the next user of the bus sees the port's own
+        // framing, and the other receivers get it back as soon as the exchange is over
+        self.port
+            .change_framing(|| self.port.bus_framing.lock(|c| c.set(None)));
+    }
+}
+
+pub(crate) static PORTS: [Port; PORT_COUNT] = [
+    Port::new(config::Config::factory_default().ports[0]),
+    Port::new(config::Config::factory_default().ports[1]),
+];
+
 #[embassy_executor::task]
 pub(super) async fn task(r0: Rs485Uart0Resources, r1: Rs485Uart1Resources) {
-    let mut config = Config::default();
-    config.baudrate = 115200;
-
-    const TX_BUFFER_SIZE: usize = 32;
-    const RX_BUFFER_SIZE: usize = 32;
+    // Room for a whole Modbus RTU frame either way, the bridges share these buffers too
+    const TX_BUFFER_SIZE: usize = modbus::MAX_ADU_SIZE;
+    const RX_BUFFER_SIZE: usize = modbus::MAX_ADU_SIZE;
 
     static TX_BUFFER_0: StaticCell<[u8; TX_BUFFER_SIZE]> = StaticCell::new();
     let tx_buf_0 = &mut TX_BUFFER_0.init([0; TX_BUFFER_SIZE])[..];
@@ -38,40 +268,141 @@ pub(super) async fn task(r0: Rs485Uart0Resources, r1: Rs485Uart1Resources) {
     static RX_BUFFER_1: StaticCell<[u8; RX_BUFFER_SIZE]> = StaticCell::new();
     let rx_buf_1 = &mut RX_BUFFER_1.init([0; RX_BUFFER_SIZE])[..];
 
-    let uart0 = BufferedUart::new(
-        r0.uart, r0.tx_pin, r0.rx_pin, IrqsUart0, tx_buf_0, rx_buf_0, config,
-    );
+    join3(
+        run(
+            0, r0.uart, r0.tx_pin, r0.rx_pin, IrqsUart0, tx_buf_0, rx_buf_0,
+        ),
+        run(
+            1, r1.uart, r1.tx_pin, r1.rx_pin, IrqsUart1, tx_buf_1, rx_buf_1,
+        ),
+        report_errors(),
+    )
+    .await;
+}
+
+/// Send the syslog server the number of receive errors on each port, when there have been any.
+async fn report_errors() {
+    let mut reported: [u32; PORT_COUNT] = core::array::from_fn(|n| stats::PORTS[n].get().errors());
 
-    let uart1 = BufferedUart::new(
-        r1.uart, r1.tx_pin, r1.rx_pin, IrqsUart1, tx_buf_1, rx_buf_1, config,
-    );
+    loop {
+        Timer::after(ERROR_REPORT_INTERVAL).await;
 
-    let (mut tx0, mut rx0) = uart0.split();
-    let (mut tx1, mut rx1) = uart1.split();
+        for (n, (reported, stats)) in reported.iter_mut().zip(&stats::PORTS).enumerate() {
+            let errors = stats.get().errors();
+            let count = errors.wrapping_sub(*reported);
+            *reported = errors;
+            if count > 0 {
+                syslog::log(
+                    Severity::Warning,
+                    format_args!(
+                        "{count} receive errors on UART {n} in the last {} s",
+                        ERROR_REPORT_INTERVAL.as_secs()
+                    ),
+                );
+            }
+        }
+    }
+}
 
-    let mut tx_tick = Ticker::every(Duration::from_secs(1));
+/// Run a port, recreating the UART whenever its line settings change.
+async fn run<T: Instance>(
+    n: usize,
+    mut uart: Peri<'static, T>,
+    mut tx_pin: Peri<'static, impl TxPin<T>>,
+    mut rx_pin: Peri<'static, impl RxPin<T>>,
+    irq: impl Binding<T::Interrupt, BufferedInterruptHandler<T>> + Copy,
+    tx_buf: &mut [u8],
+    rx_buf: &mut [u8],
+) {
+    let port = &PORTS[n];
 
-    let mut rx0_buff = [0u8; 64];
-    let mut rx1_buff = [0u8; 64];
+    // Start with the stored settings, there is no UART to reconfigure yet
+    port.config.lock(|c| c.set(config::get().ports[n]));
+    port.set_framing(config::get().framings[n]);
 
     loop {
-        match select3(
-            tx_tick.next(),
-            rx0.read(&mut rx0_buff),
-            rx1.read(&mut rx1_buff),
-        )
-        .await
-        {
-            Either3::First(_) => {
-                tx0.write_all(b"Hello from UART 0").await.unwrap();
-                tx1.write(b"Hello from UART 1").await.unwrap();
-            }
-            Either3::Second(res) => {
-                info!("UART 0 rx: {}", res);
+        let config = port.config();
+        info!("UART {} baudrate: {}", n, config.baudrate);
+        status::set_line(n, config);
+
+        let uart = BufferedUart::new(
+            uart.reborrow(),
+            tx_pin.reborrow(),
+            rx_pin.reborrow(),
+            irq,
+            &mut *tx_buf,
+            &mut *rx_buf,
+            config.into(),
+        );
+
+        select(bridge(n, port, uart), port.reconfigure.wait()).await;
+    }
+}
+
+async fn bridge(n: usize, port: &Port, uart: BufferedUart) {
+    let config = port.config();
+    let baudrate = config.baudrate;
+    let stats = &stats::PORTS[n];
+    let (mut tx, mut rx) = uart.split();
+
+    let tx_loop = async {
+        loop {
+            match port.tx.receive().await {
+                Transmit::Data(data) => match tx.write_all(&data).await {
+                    Ok(()) => {
+                        stats.record_tx(data.len());
+                        monitor::record(n, Direction::Tx, &data);
+                    }
+                    Err(e) => warn!("Failed writing to UART {}: {}", n, e),
+                },
+                Transmit::Break(duration) => {
+                    let bits = duration.as_micros() * baudrate as u64 / 1_000_000;
+                    tx.send_break(bits as u32).await;
+                }
             }
-            Either3::Third(res) => {
-                info!("UART 1 rx: {}", res);
+        }
+    };
+
+    let rx_loop = async {
+        let publisher = port.rx.immediate_publisher();
+        let mut buf = [0u8; PAYLOAD_SIZE];
+
+        loop {
+            let framing = port.rx_framing();
+            let max_len = framing.map_or(buf.len(), |f| f.frame_len(buf.len()));
+
+            // Start the read again if the framing changes while the line is quiet, as it does
+            // just before a Modbus request is sent
+            let res = match select(rx.read(&mut buf[..max_len]), port.framing_changed.wait()).await
+            {
+                Either::First(res) => res,
+                Either::Second(()) => continue,
+            };
+
+            let res = match (res, framing) {
+                (Ok(len), Some(framing)) => {
+                    framing::finish_frame(&mut rx, &mut buf, len, &framing, config.char_time())
+                        .await
+                }
+                (res, _) => res,
+            };
+
+            match res {
+                Ok(len) => {
+                    debug!("UART {} rx: {:x}", n, &buf[..len]);
+                    stats.record_rx(len);
+                    monitor::record(n, Direction::Rx, &buf[..len]);
+                    if let Ok(data) = Payload::from_slice(&buf[..len]) {
+                        publisher.publish_immediate(data);
+                    }
+                }
+                Err(e) => {
+                    warn!("UART {} rx error: {}", n, e);
+                    stats.record_error(e);
+                }
             }
         }
-    }
+    };
+
+    join(tx_loop, rx_loop).await;
 }
@@ -0,0 +1,224 @@
+use crate::{
+    config, rfc2217,
+    rs485::{PAYLOAD_SIZE, PORTS, PORT_COUNT},
+    stats,
+    syslog::{self, Severity},
+};
+use defmt::{info, warn, Format};
+use embassy_futures::select::{select, select3, Either3};
+use embassy_net::{tcp::TcpSocket, IpEndpoint, Stack};
+use embassy_sync::{
+    blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex},
+    mutex::Mutex,
+    pubsub::WaitResult,
+    signal::Signal,
+};
+use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use portable_atomic::{AtomicUsize, Ordering};
+
+/// Number of clients that can be connected to each port at once, per protocol.
+const CLIENTS_PER_PORT: usize = 2;
+
+pub(crate) const TASKS_PER_PROTOCOL: usize = PORT_COUNT * CLIENTS_PER_PORT;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum Protocol {
+    /// Bytes are passed through unmodified.
+    Raw,
+    /// Telnet with RFC 2217 COM port control.
+    Rfc2217,
+}
+
+impl Protocol {
+    /// TCP port serving RS485 port `n`, ports are numbered consecutively from the first one.
+    pub(crate) fn tcp_port(self, n: usize) -> u16 {
+        let base = match self {
+            Protocol::Raw => 4001,
+            Protocol::Rfc2217 => 4101,
+        };
+        base + n as u16
+    }
+}
+
+/// What to do when a client connects to a port that already has a client.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum ClientPolicy {
+    /// Refuse the new client.
+    Reject,
+    /// Disconnect the existing client in favour of the new one.
+    TakeOver,
+    /// Let all clients send to and receive from the port.
+    Share,
+}
+
+impl ClientPolicy {
+    pub(crate) const ALL: [ClientPolicy; 3] = [
+        ClientPolicy::Reject,
+        ClientPolicy::TakeOver,
+        ClientPolicy::Share,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ClientPolicy::Reject => "reject",
+            ClientPolicy::TakeOver => "take_over",
+            ClientPolicy::Share => "share",
+        }
+    }
+}
+
+static ACTIVE_CLIENTS: [AtomicUsize; PORT_COUNT] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+static TAKE_OVER: [Signal<CriticalSectionRawMutex, ()>; PORT_COUNT] =
+    [Signal::new(), Signal::new()];
+
+#[embassy_executor::task(pool_size = 2 * TASKS_PER_PROTOCOL)]
+pub(super) async fn task(stack: Stack<'static>, n: usize, protocol: Protocol) {
+    let tcp_port = protocol.tcp_port(n);
+
+    let mut rx_buffer = [0; 512];
+    let mut tx_buffer = [0; 512];
+
+    loop {
+        let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+        socket.set_timeout(Some(Duration::from_secs(60)));
+        socket.set_keep_alive(Some(Duration::from_secs(10)));
+
+        if let Err(e) = socket.accept(tcp_port).await {
+            warn!("Serial server {} accept failed: {}", n, e);
+            continue;
+        }
+        stats::record_accept();
+
+        let remote = socket.remote_endpoint();
+        info!("Serial server {} client connected: {}", n, remote);
+        log_client(n, protocol, remote, "connected");
+
+        if claim(n).await {
+            // The connection cannot survive the link going down, or the board moving network
+            let _ = select(serve(n, protocol, &mut socket), stack.wait_link_down()).await;
+            ACTIVE_CLIENTS[n].fetch_sub(1, Ordering::Relaxed);
+        } else {
+            info!("Serial server {} is busy, rejecting client", n);
+        }
+
+        info!("Serial server {} client disconnected", n);
+        log_client(n, protocol, remote, "disconnected");
+
+        socket.abort();
+        let _ = socket.flush().await;
+    }
+}
+
+fn log_client(n: usize, protocol: Protocol, remote: Option<IpEndpoint>, event: &str) {
+    if let Some(remote) = remote {
+        let protocol = match protocol {
+            Protocol::Raw => "Raw",
+            Protocol::Rfc2217 => "RFC 2217",
+        };
+        syslog::log(
+            Severity::Informational,
+            format_args!("{protocol} client {remote} {event} on port {n}"),
+        );
+    }
+}
+
+/// Register a new client on a port according to the client policy.
+async fn claim(n: usize) -> bool {
+    let active = &ACTIVE_CLIENTS[n];
+
+    match config::get().client_policy {
+        ClientPolicy::Reject => {
+            if active.load(Ordering::Relaxed) > 0 {
+                return false;
+            }
+        }
+        ClientPolicy::TakeOver => {
+            while active.load(Ordering::Relaxed) > 0 {
+                TAKE_OVER[n].signal(());
+                Timer::after_millis(10).await;
+            }
+            TAKE_OVER[n].reset();
+        }
+        ClientPolicy::Share => {}
+    }
+
+    active.fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+struct Disconnected {}
+
+impl From<embassy_net::tcp::Error> for Disconnected {
+    fn from(_: embassy_net::tcp::Error) -> Self {
+        Disconnected {}
+    }
+}
+
+async fn serve(
+    n: usize,
+    protocol: Protocol,
+    socket: &mut TcpSocket<'_>,
+) -> Result<(), Disconnected> {
+    let port = &PORTS[n];
+    let Some(mut subscriber) = port.subscribe() else {
+        warn!("Serial server {} has no free receivers", n);
+        return Err(Disconnected {});
+    };
+
+    let (mut reader, writer) = socket.split();
+    let writer = Mutex::<NoopRawMutex, _>::new(writer);
+
+    if protocol == Protocol::Rfc2217 {
+        writer.lock().await.write_all(rfc2217::GREETING).await?;
+    }
+
+    let to_line = async {
+        let mut session = rfc2217::Session::new();
+        let mut buf = [0u8; rfc2217::RECEIVE_SIZE];
+        loop {
+            let n = reader.read(&mut buf).await?;
+            if n == 0 {
+                return Err(Disconnected {});
+            }
+
+            match protocol {
+                Protocol::Raw => port.write(&buf[..n]).await,
+                Protocol::Rfc2217 => {
+                    let mut reply = rfc2217::Reply::new();
+                    session.receive(port, &buf[..n], &mut reply).await;
+                    if !reply.is_empty() {
+                        writer.lock().await.write_all(&reply).await?;
+                    }
+                }
+            }
+        }
+    };
+
+    let from_line = async {
+        loop {
+            match subscriber.next_message().await {
+                WaitResult::Lagged(count) => {
+                    warn!("Serial server {} lagged, {} chunks lost", n, count);
+                    stats::PORTS[n].record_dropped(count);
+                }
+                WaitResult::Message(data) => match protocol {
+                    Protocol::Raw => writer.lock().await.write_all(&data).await?,
+                    Protocol::Rfc2217 => {
+                        let mut escaped = heapless::Vec::<u8, { 2 * PAYLOAD_SIZE }>::new();
+                        rfc2217::escape(&data, &mut escaped);
+                        writer.lock().await.write_all(&escaped).await?;
+                    }
+                },
+            }
+        }
+    };
+
+    match select3(to_line, from_line, TAKE_OVER[n].wait()).await {
+        Either3::First(res) | Either3::Second(res) => res,
+        Either3::Third(_) => {
+            info!("Serial server {} client replaced", n);
+            Ok(())
+        }
+    }
+}
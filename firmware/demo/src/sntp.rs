@@ -0,0 +1,152 @@
+//! SNTP (RFC 4330) client setting the [`clock`] at start up and keeping it in step after.
+//!
+//! The configured server is used if there is one, otherwise the NTP pool is looked up in DNS.
+
+use crate::{
+    clock::{self, UtcTime},
+    config,
+    syslog::{self, Severity},
+};
+use defmt::{info, warn, Format};
+use embassy_net::{
+    dns::DnsQueryType,
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Stack,
+};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+const NTP_PORT: u16 = 123;
+const POOL_HOSTNAME: &str = "pool.ntp.org";
+
+const SYNC_INTERVAL: Duration = Duration::from_secs(3600);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+const PACKET_SIZE: usize = 48;
+
+/// Leap indicator 0, version 4 and client mode.
+const CLIENT_HEADER: u8 = 0x23;
+const MODE_SERVER: u8 = 4;
+/// Set in the leap indicator when the server is not synchronised.
+const ALARM: u8 = 0xc0;
+
+/// Seconds from the start of 1900, where NTP time starts, to the Unix epoch.
+const UNIX_EPOCH: u64 = 2_208_988_800;
+
+#[derive(Format)]
+enum Error {
+    Dns,
+    Network,
+    Timeout,
+    InvalidResponse,
+}
+
+#[embassy_executor::task]
+pub(super) async fn task(stack: Stack<'static>) {
+    let mut rx_meta = [PacketMetadata::EMPTY; 2];
+    let mut rx_buffer = [0; 2 * PACKET_SIZE];
+    let mut tx_meta = [PacketMetadata::EMPTY; 1];
+    let mut tx_buffer = [0; PACKET_SIZE];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    if let Err(e) = socket.bind(0) {
+        warn!("Failed to bind SNTP socket: {}", e);
+        return;
+    }
+
+    loop {
+        stack.wait_config_up().await;
+
+        let interval = match sync(stack, &mut socket).await {
+            Ok(()) => SYNC_INTERVAL,
+            Err(e) => {
+                warn!("SNTP sync failed: {}", e);
+                RETRY_INTERVAL
+            }
+        };
+
+        Timer::after(interval).await;
+    }
+}
+
+async fn sync(stack: Stack<'_>, socket: &mut UdpSocket<'_>) -> Result<(), Error> {
+    let address = match config::get().ntp_server {
+        Some(address) => address.into(),
+        None => *stack
+            .dns_query(POOL_HOSTNAME, DnsQueryType::A)
+            .await
+            .map_err(|_| Error::Dns)?
+            .first()
+            .ok_or(Error::Dns)?,
+    };
+    let server = IpEndpoint::new(address, NTP_PORT);
+
+    // The server echoes the transmit timestamp back, which identifies the response
+    let sent = Instant::now();
+    let origin = sent.as_micros().to_be_bytes();
+
+    let mut request = [0; PACKET_SIZE];
+    request[0] = CLIENT_HEADER;
+    request[40..48].copy_from_slice(&origin);
+
+    socket
+        .send_to(&request, server)
+        .await
+        .map_err(|_| Error::Network)?;
+
+    let mut response = [0; PACKET_SIZE];
+    loop {
+        let (len, meta) = with_timeout(RESPONSE_TIMEOUT, socket.recv_from(&mut response))
+            .await
+            .map_err(|_| Error::Timeout)?
+            .map_err(|_| Error::Network)?;
+        let received = Instant::now();
+
+        // Skip anything else, such as late responses to earlier requests
+        if meta.endpoint.addr != address || len < PACKET_SIZE || response[24..32] != origin {
+            continue;
+        }
+
+        let stratum = response[1];
+        if response[0] & 0x07 != MODE_SERVER || response[0] & ALARM == ALARM || stratum == 0 {
+            return Err(Error::InvalidResponse);
+        }
+
+        // Take the time the server handled the request to be the middle of the round trip
+        let server_received = timestamp(&response[32..40]);
+        let server_sent = timestamp(&response[40..48]);
+        let server_time = (server_received + server_sent) / 2;
+        let local_time = Instant::from_micros((sent.as_micros() + received.as_micros()) / 2);
+
+        let was_valid = clock::is_valid();
+        clock::set(UtcTime::from_unix_micros(server_time), local_time);
+
+        if !was_valid {
+            info!("Clock set from {}", server);
+            syslog::log(Severity::Notice, format_args!("Clock set from {server}"));
+        }
+        return Ok(());
+    }
+}
+
+/// Convert an NTP timestamp to microseconds since the Unix epoch.
+fn timestamp(bytes: &[u8]) -> u64 {
+    let secs = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64;
+    let fraction = u32::from_be_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as u64;
+
+    // Times before the Unix epoch are taken to be after the NTP era rolls over in 2036
+    let secs = if secs < UNIX_EPOCH {
+        secs + (1 << 32)
+    } else {
+        secs
+    };
+
+    (secs - UNIX_EPOCH) * 1_000_000 + ((fraction * 1_000_000) >> 32)
+}
@@ -0,0 +1,172 @@
+//! Counters of what the board has done since it was reset, for monitoring.
+
+use crate::rs485::PORT_COUNT;
+use core::cell::Cell;
+use defmt::{info, Format};
+use embassy_rp::{pac, uart::Error};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use portable_atomic::{AtomicU32, Ordering};
+
+/// Written to a watchdog scratch register before a requested restart, which the reset reason
+/// registers cannot tell apart from the last hardware reset.
+const SOFTWARE_RESET_MAGIC: u32 = 0x7265_626f;
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum ResetReason {
+    PowerOn,
+    RunPin,
+    Debugger,
+    Watchdog,
+    Software,
+}
+
+impl ResetReason {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            ResetReason::PowerOn => "power_on",
+            ResetReason::RunPin => "run_pin",
+            ResetReason::Debugger => "debugger",
+            ResetReason::Watchdog => "watchdog",
+            ResetReason::Software => "software",
+        }
+    }
+}
+
+static RESET_REASON: Mutex<CriticalSectionRawMutex, Cell<ResetReason>> =
+    Mutex::new(Cell::new(ResetReason::PowerOn));
+
+/// Traffic and errors on an RS485 port.
+pub(crate) struct PortStats {
+    rx_bytes: AtomicU32,
+    tx_bytes: AtomicU32,
+    overrun: AtomicU32,
+    break_: AtomicU32,
+    parity: AtomicU32,
+    framing: AtomicU32,
+    /// Chunks or frames of received data a receiver missed because it was not keeping up, their
+    /// sizes are gone by the time this is known.
+    dropped_chunks: AtomicU32,
+}
+
+/// The values of [`PortStats`] at one moment.
+#[derive(Clone, Copy)]
+pub(crate) struct PortCounts {
+    pub(crate) rx_bytes: u32,
+    pub(crate) tx_bytes: u32,
+    pub(crate) overrun: u32,
+    pub(crate) break_: u32,
+    pub(crate) parity: u32,
+    pub(crate) framing: u32,
+    pub(crate) dropped_chunks: u32,
+}
+
+impl PortCounts {
+    /// UART errors of every kind.
+    pub(crate) fn errors(&self) -> u32 {
+        self.overrun + self.break_ + self.parity + self.framing
+    }
+}
+
+impl PortStats {
+    const fn new() -> Self {
+        Self {
+            rx_bytes: AtomicU32::new(0),
+            tx_bytes: AtomicU32::new(0),
+            overrun: AtomicU32::new(0),
+            break_: AtomicU32::new(0),
+            parity: AtomicU32::new(0),
+            framing: AtomicU32::new(0),
+            dropped_chunks: AtomicU32::new(0),
+        }
+    }
+
+    pub(crate) fn record_rx(&self, len: usize) {
+        self.rx_bytes.fetch_add(len as u32, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_tx(&self, len: usize) {
+        self.tx_bytes.fetch_add(len as u32, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self, error: Error) {
+        let counter = match error {
+            Error::Overrun => &self.overrun,
+            Error::Break => &self.break_,
+            Error::Parity => &self.parity,
+            Error::Framing => &self.framing,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Note that a receiver missed `chunks` chunks of received data.
+    pub(crate) fn record_dropped(&self, chunks: u64) {
+        let chunks = u32::try_from(chunks).unwrap_or(u32::MAX);
+        let _ = self
+            .dropped_chunks
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                Some(v.saturating_add(chunks))
+            });
+    }
+
+    pub(crate) fn get(&self) -> PortCounts {
+        PortCounts {
+            rx_bytes: self.rx_bytes.load(Ordering::Relaxed),
+            tx_bytes: self.tx_bytes.load(Ordering::Relaxed),
+            overrun: self.overrun.load(Ordering::Relaxed),
+            break_: self.break_.load(Ordering::Relaxed),
+            parity: self.parity.load(Ordering::Relaxed),
+            framing: self.framing.load(Ordering::Relaxed),
+            dropped_chunks: self.dropped_chunks.load(Ordering::Relaxed),
+        }
+    }
+}
+
+pub(crate) static PORTS: [PortStats; PORT_COUNT] = [const { PortStats::new() }; PORT_COUNT];
+
+/// TCP connections accepted by all of the servers.
+pub(crate) static TCP_ACCEPTED: AtomicU32 = AtomicU32::new(0);
+
+/// DHCP leases obtained, after the link comes up or a lease is lost. Renewals of a lease are
+/// handled inside the network stack and are not seen here.
+pub(crate) static DHCP_LEASES: AtomicU32 = AtomicU32::new(0);
+
+pub(crate) fn record_accept() {
+    TCP_ACCEPTED.fetch_add(1, Ordering::Relaxed);
+}
+
+pub(crate) fn record_dhcp_lease() {
+    DHCP_LEASES.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Find out why the board was last reset, which must be done once at power on.
+pub(crate) fn init() {
+    let watchdog = pac::WATCHDOG.reason().read();
+    let chip = pac::VREG_AND_CHIP_RESET.chip_reset().read();
+
+    let reason = if pac::WATCHDOG.scratch0().read() == SOFTWARE_RESET_MAGIC {
+        ResetReason::Software
+    } else if watchdog.timer() || watchdog.force() {
+        ResetReason::Watchdog
+    } else if chip.had_psm_restart() {
+        ResetReason::Debugger
+    } else if chip.had_run() {
+        ResetReason::RunPin
+    } else {
+        ResetReason::PowerOn
+    };
+    pac::WATCHDOG.scratch0().write_value(0);
+
+    info!("Reset reason: {}", reason);
+    RESET_REASON.lock(|c| c.set(reason));
+}
+
+pub(crate) fn reset_reason() -> ResetReason {
+    RESET_REASON.lock(Cell::get)
+}
+
+/// Restart the board, recording that it was asked to.
+pub(crate) fn reset() -> ! {
+    pac::WATCHDOG.scratch0().write_value(SOFTWARE_RESET_MAGIC);
+    cortex_m::peripheral::SCB::sys_reset()
+}
@@ -0,0 +1,89 @@
+//! The state of the board at a glance, for the display.
+//!
+//! Each part is kept up to date by the task that owns it: the network state by the ethernet task
+//! and the line settings in use by the RS485 task. Traffic and error counts are taken from
+//! [`stats`] when a snapshot is made.
+
+use crate::{
+    ethernet::Ipv4Status,
+    rs485::{LineConfig, PORT_COUNT},
+    stats::{self, PortCounts},
+};
+use core::cell::Cell;
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    signal::Signal,
+};
+use embassy_time::Instant;
+
+#[derive(Clone, Copy)]
+struct Shared {
+    link_up: bool,
+    ipv4: Option<Ipv4Status>,
+    lines: [Option<LineConfig>; PORT_COUNT],
+}
+
+static SHARED: Mutex<CriticalSectionRawMutex, Cell<Shared>> = Mutex::new(Cell::new(Shared {
+    link_up: false,
+    ipv4: None,
+    lines: [None; PORT_COUNT],
+}));
+
+static CHANGED: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// A snapshot of the state of the board.
+#[derive(Clone, Copy)]
+pub(crate) struct Status {
+    pub(crate) time: Instant,
+    pub(crate) link_up: bool,
+    /// The address in use, `None` while there is none.
+    pub(crate) ipv4: Option<Ipv4Status>,
+    pub(crate) ports: [PortStatus; PORT_COUNT],
+}
+
+#[derive(Clone, Copy)]
+pub(crate) struct PortStatus {
+    /// The line settings the UART is running with, `None` before it has started.
+    pub(crate) line: Option<LineConfig>,
+    pub(crate) counts: PortCounts,
+}
+
+pub(crate) fn get() -> Status {
+    let shared = SHARED.lock(Cell::get);
+
+    Status {
+        time: Instant::now(),
+        link_up: shared.link_up,
+        ipv4: shared.ipv4,
+        ports: core::array::from_fn(|n| PortStatus {
+            line: shared.lines[n],
+            counts: stats::PORTS[n].get(),
+        }),
+    }
+}
+
+fn update(f: impl FnOnce(&mut Shared)) {
+    SHARED.lock(|c| {
+        let mut shared = c.get();
+        f(&mut shared);
+        c.set(shared);
+    });
+    CHANGED.signal(());
+}
+
+/// Wait until the network state or line settings change. Only one task may wait at a time.
+pub(crate) async fn changed() {
+    CHANGED.wait().await
+}
+
+pub(crate) fn set_link_up(link_up: bool) {
+    update(|s| s.link_up = link_up);
+}
+
+pub(crate) fn set_ipv4(ipv4: Option<Ipv4Status>) {
+    update(|s| s.ipv4 = ipv4);
+}
+
+pub(crate) fn set_line(n: usize, line: LineConfig) {
+    update(|s| s.lines[n] = Some(line));
+}
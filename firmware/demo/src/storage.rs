@@ -0,0 +1,212 @@
+//! Append-only log of records kept in a reserved region at the end of flash.
+//!
+//! Each record takes one flash page and is written to the page after the previous one, wrapping at
+//! the end of the region, so writes are spread over the whole region. A sector is only erased when
+//! the log moves into it, the newest record is always in another sector so an interrupted write or
+//! erase leaves it intact.
+
+use defmt::{debug, Format};
+use embassy_rp::{
+    flash::{self, Blocking, Flash, ERASE_SIZE, PAGE_SIZE},
+    peripherals::FLASH,
+    Peri,
+};
+
+pub(crate) const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+/// Must match the space left at the end of `FLASH` in `memory.x`.
+const REGION_SIZE: usize = 16 * 1024;
+const REGION_START: usize = FLASH_SIZE - REGION_SIZE;
+
+const SLOT_SIZE: usize = PAGE_SIZE;
+const SLOT_COUNT: usize = REGION_SIZE / SLOT_SIZE;
+
+const MAGIC: u32 = u32::from_le_bytes(*b"p485");
+
+/// Magic, sequence number, record length, padding and CRC.
+const HEADER_SIZE: usize = 16;
+
+pub(crate) const MAX_RECORD_SIZE: usize = SLOT_SIZE - HEADER_SIZE;
+
+#[derive(Debug, Format)]
+pub(crate) enum Error {
+    Flash(flash::Error),
+    /// The record does not fit in a flash page.
+    TooLarge,
+}
+
+impl From<flash::Error> for Error {
+    fn from(e: flash::Error) -> Self {
+        Error::Flash(e)
+    }
+}
+
+struct Latest {
+    slot: usize,
+    sequence: u32,
+}
+
+pub(crate) struct Storage {
+    flash: Flash<'static, FLASH, Blocking, FLASH_SIZE>,
+    latest: Option<Latest>,
+}
+
+impl Storage {
+    pub(crate) fn new(flash: Peri<'static, FLASH>) -> Self {
+        let mut storage = Self {
+            flash: Flash::new_blocking(flash),
+            latest: None,
+        };
+
+        let mut buf = [0; SLOT_SIZE];
+        for slot in 0..SLOT_COUNT {
+            let Some(sequence) = storage
+                .read_slot(slot, &mut buf)
+                .map(|(sequence, _)| sequence)
+            else {
+                continue;
+            };
+
+            if storage
+                .latest
+                .as_ref()
+                .is_none_or(|latest| sequence.wrapping_sub(latest.sequence) as i32 > 0)
+            {
+                storage.latest = Some(Latest { slot, sequence });
+            }
+        }
+
+        if let Some(latest) = &storage.latest {
+            debug!(
+                "Newest stored record is {} in slot {}",
+                latest.sequence, latest.slot
+            );
+        }
+
+        storage
+    }
+
+    /// The 64 bit unique ID of the flash chip.
+    pub(crate) fn unique_id(&mut self) -> Result<[u8; 8], Error> {
+        let mut id = [0; 8];
+        self.flash.blocking_unique_id(&mut id)?;
+        Ok(id)
+    }
+
+    /// Read the newest record into `buf`, returns `None` if there is no valid record.
+    pub(crate) fn read(&mut self, buf: &mut [u8; MAX_RECORD_SIZE]) -> Option<usize> {
+        let slot = self.latest.as_ref()?.slot;
+
+        let mut page = [0; SLOT_SIZE];
+        let (_, record) = self.read_slot(slot, &mut page)?;
+        buf[..record.len()].copy_from_slice(record);
+        Some(record.len())
+    }
+
+    /// Append a record, it replaces the previous one once written.
+    pub(crate) fn write(&mut self, record: &[u8]) -> Result<(), Error> {
+        if record.len() > MAX_RECORD_SIZE {
+            return Err(Error::TooLarge);
+        }
+
+        let sequence = self
+            .latest
+            .as_ref()
+            .map_or(0, |latest| latest.sequence.wrapping_add(1));
+
+        let mut page = [0xff; SLOT_SIZE];
+        page[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        page[4..8].copy_from_slice(&sequence.to_le_bytes());
+        page[8..10].copy_from_slice(&(record.len() as u16).to_le_bytes());
+        page[HEADER_SIZE..HEADER_SIZE + record.len()].copy_from_slice(record);
+        let crc = record_crc(&page, record.len());
+        page[12..16].copy_from_slice(&crc.to_le_bytes());
+
+        let slot = self.next_free_slot()?;
+        self.flash
+            .blocking_write(slot_offset(slot), &page[..HEADER_SIZE + record.len()])?;
+
+        debug!("Stored record {} in slot {}", sequence, slot);
+        self.latest = Some(Latest { slot, sequence });
+
+        Ok(())
+    }
+
+    /// Find the next blank slot after the newest record, erasing the following sector once the
+    /// current one is used up.
+    fn next_free_slot(&mut self) -> Result<usize, Error> {
+        let mut slot = self.latest.as_ref().map_or(0, |latest| latest.slot + 1);
+
+        loop {
+            slot %= SLOT_COUNT;
+            let offset = slot_offset(slot);
+
+            if offset as usize % ERASE_SIZE == 0 {
+                self.flash
+                    .blocking_erase(offset, offset + ERASE_SIZE as u32)?;
+                return Ok(slot);
+            }
+
+            // Skip over anything left by an interrupted write
+            let mut page = [0; SLOT_SIZE];
+            self.flash.blocking_read(offset, &mut page)?;
+            if page.iter().all(|b| *b == 0xff) {
+                return Ok(slot);
+            }
+
+            slot += 1;
+        }
+    }
+
+    /// Read a slot, returning its sequence number and record if it holds a valid one.
+    fn read_slot<'a>(
+        &mut self,
+        slot: usize,
+        page: &'a mut [u8; SLOT_SIZE],
+    ) -> Option<(u32, &'a [u8])> {
+        self.flash.blocking_read(slot_offset(slot), page).ok()?;
+        let page: &'a [u8; SLOT_SIZE] = page;
+
+        let word = |offset: usize| u32::from_le_bytes(page[offset..offset + 4].try_into().unwrap());
+
+        if word(0) != MAGIC {
+            return None;
+        }
+
+        let len = u16::from_le_bytes([page[8], page[9]]) as usize;
+        if len > MAX_RECORD_SIZE || word(12) != record_crc(page, len) {
+            return None;
+        }
+
+        Some((word(4), &page[HEADER_SIZE..HEADER_SIZE + len]))
+    }
+}
+
+fn slot_offset(slot: usize) -> u32 {
+    (REGION_START + slot * SLOT_SIZE) as u32
+}
+
+/// CRC of the sequence number, length and record.
+fn record_crc(page: &[u8], len: usize) -> u32 {
+    crc32(
+        page[4..10]
+            .iter()
+            .chain(&page[HEADER_SIZE..HEADER_SIZE + len]),
+    )
+}
+
+/// CRC-32 as used by Ethernet and zip (reflected polynomial 0xEDB88320).
+fn crc32<'a>(data: impl IntoIterator<Item = &'a u8>) -> u32 {
+    let mut crc = 0xffff_ffff_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            if crc & 1 != 0 {
+                crc = (crc >> 1) ^ 0xedb8_8320;
+            } else {
+                crc >>= 1;
+            }
+        }
+    }
+    !crc
+}
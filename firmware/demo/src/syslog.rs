@@ -0,0 +1,173 @@
+//! Forwarding of notable events to a syslog server over UDP, in the RFC 5424 format.
+//!
+//! Events can be logged from anywhere with [`log`] and are queued until the network is up. Events
+//! are dropped if no server is configured, they are less severe than the configured minimum, or
+//! the queue is full.
+
+use crate::{backlight, clock, config, identity};
+use core::fmt::{Arguments, Write as _};
+use defmt::{warn, Format};
+use embassy_net::{
+    udp::{PacketMetadata, UdpSocket},
+    IpEndpoint, Ipv4Address, Stack,
+};
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel};
+use embassy_time::Instant;
+use heapless::String;
+
+pub(crate) const DEFAULT_PORT: u16 = 514;
+/// Least severe events sent to a server unless set otherwise, which is every event logged.
+pub(crate) const DEFAULT_MIN_SEVERITY: Severity = Severity::Informational;
+
+/// Events are sent as coming from local use facility 0.
+const FACILITY: u8 = 16;
+
+const APP_NAME: &str = "pi485";
+
+const MAX_MESSAGE_LEN: usize = 128;
+const MAX_PACKET_SIZE: usize = 256;
+
+/// Severity levels of RFC 5424, from the most severe.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Format)]
+pub(crate) enum Severity {
+    Emergency = 0,
+    Alert = 1,
+    Critical = 2,
+    Error = 3,
+    Warning = 4,
+    Notice = 5,
+    Informational = 6,
+    Debug = 7,
+}
+
+impl Severity {
+    pub(crate) const ALL: [Severity; 8] = [
+        Severity::Emergency,
+        Severity::Alert,
+        Severity::Critical,
+        Severity::Error,
+        Severity::Warning,
+        Severity::Notice,
+        Severity::Informational,
+        Severity::Debug,
+    ];
+
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Severity::Emergency => "emergency",
+            Severity::Alert => "alert",
+            Severity::Critical => "critical",
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+            Severity::Notice => "notice",
+            Severity::Informational => "informational",
+            Severity::Debug => "debug",
+        }
+    }
+
+    pub(crate) fn from_u8(value: u8) -> Option<Self> {
+        Some(match value {
+            0 => Severity::Emergency,
+            1 => Severity::Alert,
+            2 => Severity::Critical,
+            3 => Severity::Error,
+            4 => Severity::Warning,
+            5 => Severity::Notice,
+            6 => Severity::Informational,
+            7 => Severity::Debug,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct SyslogServer {
+    pub(crate) address: Ipv4Address,
+    pub(crate) port: u16,
+    /// Least severe events that are sent.
+    pub(crate) min_severity: Severity,
+}
+
+struct Event {
+    severity: Severity,
+    time: Instant,
+    message: String<MAX_MESSAGE_LEN>,
+}
+
+static EVENTS: Channel<CriticalSectionRawMutex, Event, 16> = Channel::new();
+
+/// Queue an event for the syslog server, messages that are too long are cut short.
+///
+/// Warnings and more severe events also light the display.
+pub(crate) fn log(severity: Severity, message: Arguments) {
+    if severity <= Severity::Warning {
+        backlight::alarm();
+    }
+
+    let Some(server) = config::get().syslog else {
+        return;
+    };
+    // Lower values are more severe
+    if severity > server.min_severity {
+        return;
+    }
+
+    let mut event = Event {
+        severity,
+        time: Instant::now(),
+        message: String::new(),
+    };
+    let _ = event.message.write_fmt(message);
+
+    if EVENTS.try_send(event).is_err() {
+        warn!("Syslog queue full, event dropped");
+    }
+}
+
+#[embassy_executor::task]
+pub(super) async fn task(stack: Stack<'static>) {
+    let hostname = identity::hostname();
+
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 0];
+    let mut tx_meta = [PacketMetadata::EMPTY; 4];
+    let mut tx_buffer = [0; 2 * MAX_PACKET_SIZE];
+
+    let mut socket = UdpSocket::new(
+        stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+
+    if let Err(e) = socket.bind(0) {
+        warn!("Failed to bind syslog socket: {}", e);
+        return;
+    }
+
+    loop {
+        let event = EVENTS.receive().await;
+
+        // The server may have been removed since the event was queued
+        let Some(server) = config::get().syslog else {
+            continue;
+        };
+
+        stack.wait_config_up().await;
+
+        let mut packet = String::<MAX_PACKET_SIZE>::new();
+        let _ = write!(packet, "<{}>1 ", FACILITY * 8 + event.severity as u8);
+        // The time is left out until the clock has been set
+        let _ = match clock::at(event.time) {
+            Some(time) => write!(packet, "{time}"),
+            None => packet.push('-').map_err(|_| core::fmt::Error),
+        };
+        let _ = write!(packet, " {hostname} {APP_NAME} - - - {}", event.message);
+
+        let endpoint = IpEndpoint::new(server.address.into(), server.port);
+        if let Err(e) = socket.send_to(packet.as_bytes(), endpoint).await {
+            warn!("Failed to send syslog event: {}", e);
+        }
+    }
+}
@@ -0,0 +1,369 @@
+//! WebSocket (RFC 6455) terminal on the RS485 ports, reached through the HTTP server at
+//! `/ws/port/<n>`.
+//!
+//! By default data received on the line is sent as binary messages and every message from the
+//! client is sent on the line as is. Options in the query string switch to text messages, one for
+//! each chunk or frame received:
+//! - `hex`: bytes are shown as hex, and messages from the client are read as hex too
+//! - `timestamps`: each message starts with the time in UTC, or the time since power on in seconds
+//!   until the clock has been set
+
+use crate::{
+    clock,
+    rs485::{RxSubscriber, PAYLOAD_SIZE, PORTS},
+    stats,
+    syslog::{self, Severity},
+};
+use core::fmt::Write as _;
+use defmt::{info, warn};
+use embassy_futures::select::{select, Either};
+use embassy_net::{
+    tcp::{TcpSocket, TcpWriter},
+    IpEndpoint,
+};
+use embassy_sync::{blocking_mutex::raw::NoopRawMutex, mutex::Mutex, pubsub::WaitResult};
+use embassy_time::{Duration, Instant};
+use embedded_io_async::{Read, ReadExactError, Write};
+use heapless::{String, Vec};
+
+const GUID: &[u8] = b"258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xa;
+
+/// Set in the first byte of the last frame of a message, messages are never split when sending.
+const FIN: u8 = 0x80;
+const MASKED: u8 = 0x80;
+
+/// Largest message accepted from the client.
+const MAX_MESSAGE_SIZE: usize = 512;
+
+/// Close status sent when a message from the client is larger than [`MAX_MESSAGE_SIZE`].
+const STATUS_MESSAGE_TOO_BIG: u16 = 1009;
+
+#[derive(Clone, Copy, Default)]
+pub(crate) struct Options {
+    hex: bool,
+    timestamps: bool,
+}
+
+impl Options {
+    /// Read the options from a query string, unknown ones are ignored.
+    pub(crate) fn from_query(query: &str) -> Self {
+        let mut options = Self::default();
+        for option in query.split('&') {
+            match option {
+                "hex" => options.hex = true,
+                "timestamps" => options.timestamps = true,
+                _ => {}
+            }
+        }
+        options
+    }
+}
+
+struct Disconnected {}
+
+impl From<embassy_net::tcp::Error> for Disconnected {
+    fn from(_: embassy_net::tcp::Error) -> Self {
+        Disconnected {}
+    }
+}
+
+impl From<ReadExactError<embassy_net::tcp::Error>> for Disconnected {
+    fn from(_: ReadExactError<embassy_net::tcp::Error>) -> Self {
+        Disconnected {}
+    }
+}
+
+/// Complete the opening handshake and run the terminal on port `n` until the client goes away.
+pub(crate) async fn serve(socket: &mut TcpSocket<'_>, n: usize, key: &str, options: Options) {
+    let Some(mut subscriber) = PORTS[n].subscribe() else {
+        warn!("WebSocket {} has no free receivers", n);
+        let _ = socket
+            .write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\n\r\n")
+            .await;
+        return;
+    };
+
+    let mut response = String::<160>::new();
+    let _ = write!(
+        response,
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(key)
+    );
+    if socket.write_all(response.as_bytes()).await.is_err() {
+        return;
+    }
+
+    info!("WebSocket connected to port {}", n);
+    let remote = socket.remote_endpoint();
+    log_client(n, remote, "connected");
+
+    // The terminal may sit idle for a long time
+    socket.set_timeout(None);
+    socket.set_keep_alive(Some(Duration::from_secs(10)));
+
+    let _ = run(socket, n, &mut subscriber, options).await;
+    info!("WebSocket disconnected from port {}", n);
+    log_client(n, remote, "disconnected");
+}
+
+fn log_client(n: usize, remote: Option<IpEndpoint>, event: &str) {
+    if let Some(remote) = remote {
+        syslog::log(
+            Severity::Informational,
+            format_args!("WebSocket client {remote} {event} on port {n}"),
+        );
+    }
+}
+
+async fn run(
+    socket: &mut TcpSocket<'_>,
+    n: usize,
+    subscriber: &mut RxSubscriber<'_>,
+    options: Options,
+) -> Result<(), Disconnected> {
+    let port = &PORTS[n];
+    let (mut reader, writer) = socket.split();
+    let writer = Mutex::<NoopRawMutex, _>::new(writer);
+
+    let to_line = async {
+        let mut buf = [0u8; MAX_MESSAGE_SIZE];
+
+        loop {
+            let mut head = [0u8; 2];
+            reader.read_exact(&mut head).await?;
+            let opcode = head[0] & 0x0f;
+
+            let len = match head[1] & 0x7f {
+                126 => {
+                    let mut len = [0u8; 2];
+                    reader.read_exact(&mut len).await?;
+                    u16::from_be_bytes(len) as u64
+                }
+                127 => {
+                    let mut len = [0u8; 8];
+                    reader.read_exact(&mut len).await?;
+                    u64::from_be_bytes(len)
+                }
+                len => len as u64,
+            };
+
+            // Clients must always mask their frames
+            if head[1] & MASKED == 0 {
+                return Err(Disconnected {});
+            }
+
+            // Compared before narrowing, so a huge length can't wrap round to a small one
+            if len > buf.len() as u64 {
+                warn!("WebSocket {} message too long: {} bytes", n, len);
+                let status = STATUS_MESSAGE_TOO_BIG.to_be_bytes();
+                send(&mut *writer.lock().await, OP_CLOSE, &status).await?;
+                return Ok(());
+            }
+            let len = len as usize;
+
+            let mut mask = [0u8; 4];
+            reader.read_exact(&mut mask).await?;
+
+            let payload = &mut buf[..len];
+            reader.read_exact(payload).await?;
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+
+            match opcode {
+                OP_TEXT | OP_BINARY | OP_CONTINUATION if options.hex => match parse_hex(payload) {
+                    Some(data) => port.write(&data).await,
+                    None => warn!("WebSocket {} sent invalid hex", n),
+                },
+                OP_TEXT | OP_BINARY | OP_CONTINUATION => port.write(payload).await,
+                OP_PING => send(&mut *writer.lock().await, OP_PONG, payload).await?,
+                OP_PONG => {}
+                OP_CLOSE => {
+                    // Echo the status code back to complete the closing handshake
+                    send(&mut *writer.lock().await, OP_CLOSE, payload).await?;
+                    return Ok(());
+                }
+                _ => return Err(Disconnected {}),
+            }
+        }
+    };
+
+    let from_line = async {
+        loop {
+            match subscriber.next_message().await {
+                WaitResult::Lagged(count) => {
+                    warn!("WebSocket {} lagged, {} chunks lost", n, count);
+                    stats::PORTS[n].record_dropped(count);
+                }
+                WaitResult::Message(data) if !options.hex && !options.timestamps => {
+                    send(&mut *writer.lock().await, OP_BINARY, &data).await?;
+                }
+                WaitResult::Message(data) => {
+                    let text = format_text(&data, options);
+                    send(&mut *writer.lock().await, OP_TEXT, text.as_bytes()).await?;
+                }
+            }
+        }
+    };
+
+    match select(to_line, from_line).await {
+        Either::First(res) | Either::Second(res) => res,
+    }
+}
+
+async fn send(writer: &mut TcpWriter<'_>, opcode: u8, payload: &[u8]) -> Result<(), Disconnected> {
+    match payload.len() {
+        len @ 0..=125 => writer.write_all(&[FIN | opcode, len as u8]).await?,
+        len => {
+            let len = (len as u16).to_be_bytes();
+            writer
+                .write_all(&[FIN | opcode, 126, len[0], len[1]])
+                .await?
+        }
+    }
+    writer.write_all(payload).await?;
+    Ok(())
+}
+
+/// Received data as text, with bytes that are not printable ASCII escaped when not in hex.
+fn format_text(data: &[u8], options: Options) -> String<{ 4 * PAYLOAD_SIZE + 32 }> {
+    let mut text = String::new();
+
+    if options.timestamps {
+        let now = Instant::now();
+        let _ = match clock::at(now) {
+            Some(time) => write!(text, "{time} "),
+            None => {
+                let millis = now.as_millis();
+                write!(text, "{}.{:03} ", millis / 1000, millis % 1000)
+            }
+        };
+    }
+
+    for byte in data {
+        let _ = match byte {
+            _ if options.hex => write!(text, "{byte:02x} "),
+            b' '..=b'~' => text.push(*byte as char).map_err(|_| core::fmt::Error),
+            _ => write!(text, "\\x{byte:02x}"),
+        };
+    }
+
+    text
+}
+
+/// Hex digits in pairs, whitespace is ignored.
+fn parse_hex(text: &[u8]) -> Option<Vec<u8, { MAX_MESSAGE_SIZE / 2 }>> {
+    let mut data = Vec::new();
+    let mut digits = text
+        .iter()
+        .filter(|b| !b.is_ascii_whitespace())
+        .map(|b| (*b as char).to_digit(16));
+
+    while let Some(high) = digits.next() {
+        let low = digits.next()??;
+        data.push((high? << 4 | low) as u8).ok()?;
+    }
+
+    Some(data)
+}
+
+/// The value of `Sec-WebSocket-Accept` for the key sent by the client.
+fn accept_key(key: &str) -> String<28> {
+    let mut input = Vec::<u8, 128>::new();
+    let _ = input.extend_from_slice(key.trim().as_bytes());
+    let _ = input.extend_from_slice(GUID);
+    base64(&sha1(&input))
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+    // The data is followed by a one bit, zeros and the length in bits, to a multiple of 64 bytes
+    let blocks = (data.len() + 8) / 64 + 1;
+
+    for i in 0..blocks {
+        let mut block = [0u8; 64];
+        for (j, byte) in block.iter_mut().enumerate() {
+            let k = i * 64 + j;
+            *byte = match k.cmp(&data.len()) {
+                core::cmp::Ordering::Less => data[k],
+                core::cmp::Ordering::Equal => 0x80,
+                core::cmp::Ordering::Greater => 0,
+            };
+        }
+        if i == blocks - 1 {
+            block[56..].copy_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+        }
+
+        let mut w = [0u32; 80];
+        for (word, bytes) in w.iter_mut().zip(block.chunks_exact(4)) {
+            *word = u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        }
+        for t in 16..80 {
+            w[t] = (w[t - 3] ^ w[t - 8] ^ w[t - 14] ^ w[t - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (t, word) in w.iter().enumerate() {
+            let (f, k) = match t {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*word);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (h, x) in h.iter_mut().zip([a, b, c, d, e]) {
+            *h = h.wrapping_add(x);
+        }
+    }
+
+    let mut digest = [0u8; 20];
+    for (bytes, h) in digest.chunks_exact_mut(4).zip(h) {
+        bytes.copy_from_slice(&h.to_be_bytes());
+    }
+    digest
+}
+
+fn base64(data: &[u8; 20]) -> String<28> {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+    let mut text = String::new();
+    for chunk in data.chunks(3) {
+        let bytes = [
+            chunk[0],
+            chunk.get(1).copied().unwrap_or(0),
+            chunk.get(2).copied().unwrap_or(0),
+        ];
+        let n = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+
+        for i in 0..4 {
+            let c = if i <= chunk.len() {
+                ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char
+            } else {
+                '='
+            };
+            let _ = text.push(c);
+        }
+    }
+    text
+}
@@ -0,0 +1,239 @@
+//! Settings that can be changed in the field, kept in flash.
+//!
+//! Settings are stored as a version byte followed by key, length and value entries. Missing keys
+//! take their factory default and unknown keys are skipped, so settings can be added without
+//! changing the version.
+
+use crate::{
+    framing::{self, Framing},
+    rs485::Mode,
+    storage::{self, Storage, MAX_RECORD_SIZE},
+    PORT_COUNT,
+};
+use core::cell::RefCell;
+use defmt::{info, warn};
+use embassy_rp::uart::{self, DataBits, Parity, StopBits};
+use embassy_sync::{
+    blocking_mutex::{self, raw::CriticalSectionRawMutex},
+    mutex::Mutex,
+    once_lock::OnceLock,
+};
+use heapless::{String, Vec};
+
+/// Changed when the meaning of an existing key changes, records of any other version are ignored.
+const VERSION: u8 = 1;
+
+const KEY_SERIAL_NUMBER: u8 = 0x01;
+/// Settings of the first port, subsequent ports use the following keys.
+const KEY_PORT_LINE: u8 = 0x10;
+const KEY_PORT_MODE: u8 = 0x20;
+const KEY_PORT_FRAMING: u8 = 0x30;
+
+/// Longest serial number that can be stored.
+pub(crate) const MAX_SERIAL_NUMBER_LEN: usize = 32;
+
+#[derive(Clone, PartialEq, Eq)]
+pub(crate) struct Config {
+    /// Replaces the USB serial number derived from the unique ID.
+    pub(crate) serial_number: Option<String<MAX_SERIAL_NUMBER_LEN>>,
+    pub(crate) ports: [PortConfig; PORT_COUNT],
+}
+
+/// Settings a port starts with, the host may change them once the port is opened.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PortConfig {
+    pub(crate) line: uart::Config,
+    pub(crate) mode: Mode,
+    pub(crate) framing: Option<Framing>,
+}
+
+impl Config {
+    pub(crate) fn factory_default() -> Self {
+        let mut line = uart::Config::default();
+        line.baudrate = 19200;
+        line.data_bits = DataBits::DataBits8;
+        line.parity = Parity::ParityNone;
+        line.stop_bits = StopBits::STOP1;
+
+        Self {
+            serial_number: None,
+            ports: [PortConfig {
+                line,
+                mode: Mode::Bridge,
+                framing: None,
+            }; PORT_COUNT],
+        }
+    }
+
+    fn encode(&self) -> Vec<u8, MAX_RECORD_SIZE> {
+        let mut record = Vec::new();
+        let _ = record.push(VERSION);
+
+        let mut entry = |key: u8, value: &[u8]| {
+            let _ = record.extend_from_slice(&[key, value.len() as u8]);
+            let _ = record.extend_from_slice(value);
+        };
+
+        if let Some(serial_number) = &self.serial_number {
+            entry(KEY_SERIAL_NUMBER, serial_number.as_bytes());
+        }
+        for (n, port) in self.ports.iter().enumerate() {
+            let n = n as u8;
+            entry(KEY_PORT_LINE + n, &encode_line(&port.line));
+            entry(
+                KEY_PORT_MODE + n,
+                &[match port.mode {
+                    Mode::Bridge => 0,
+                    Mode::Echo => 1,
+                }],
+            );
+            entry(KEY_PORT_FRAMING + n, &framing::to_bytes(port.framing));
+        }
+
+        record
+    }
+
+    /// Returns `None` if the record is malformed or of another version.
+    fn decode(record: &[u8]) -> Option<Self> {
+        let (&VERSION, mut entries) = record.split_first()? else {
+            return None;
+        };
+
+        let mut config = Self::factory_default();
+
+        while let [key, len, rest @ ..] = entries {
+            let (value, rest) = rest.split_at_checked(*len as usize)?;
+            entries = rest;
+
+            let port = |base: u8| {
+                key.checked_sub(base)
+                    .map(usize::from)
+                    .filter(|n| *n < PORT_COUNT)
+            };
+
+            if *key == KEY_SERIAL_NUMBER {
+                let serial_number = core::str::from_utf8(value).ok()?;
+                config.serial_number = Some(serial_number.try_into().ok()?);
+            } else if let Some(n) = port(KEY_PORT_LINE) {
+                config.ports[n].line = decode_line(value)?;
+            } else if let Some(n) = port(KEY_PORT_MODE) {
+                config.ports[n].mode = match value {
+                    [0] => Mode::Bridge,
+                    [1] => Mode::Echo,
+                    _ => return None,
+                };
+            } else if let Some(n) = port(KEY_PORT_FRAMING) {
+                config.ports[n].framing = framing::from_bytes(value.try_into().ok()?)?;
+            }
+        }
+
+        entries.is_empty().then_some(config)
+    }
+}
+
+/// Baud rate as a little endian `u32`, then data bits, parity (0 none, 1 odd, 2 even) and stop
+/// bits.
+fn encode_line(line: &uart::Config) -> [u8; 7] {
+    let mut value = [0; 7];
+    value[..4].copy_from_slice(&line.baudrate.to_le_bytes());
+    value[4] = match line.data_bits {
+        DataBits::DataBits5 => 5,
+        DataBits::DataBits6 => 6,
+        DataBits::DataBits7 => 7,
+        DataBits::DataBits8 => 8,
+    };
+    value[5] = match line.parity {
+        Parity::ParityNone => 0,
+        Parity::ParityOdd => 1,
+        Parity::ParityEven => 2,
+    };
+    value[6] = match line.stop_bits {
+        StopBits::STOP1 => 1,
+        StopBits::STOP2 => 2,
+    };
+    value
+}
+
+fn decode_line(value: &[u8]) -> Option<uart::Config> {
+    let [b0, b1, b2, b3, data_bits, parity, stop_bits] = *value else {
+        return None;
+    };
+
+    let mut line = uart::Config::default();
+
+    line.baudrate = u32::from_le_bytes([b0, b1, b2, b3]);
+    if line.baudrate == 0 {
+        return None;
+    }
+
+    line.data_bits = match data_bits {
+        5 => DataBits::DataBits5,
+        6 => DataBits::DataBits6,
+        7 => DataBits::DataBits7,
+        8 => DataBits::DataBits8,
+        _ => return None,
+    };
+    line.parity = match parity {
+        0 => Parity::ParityNone,
+        1 => Parity::ParityOdd,
+        2 => Parity::ParityEven,
+        _ => return None,
+    };
+    line.stop_bits = match stop_bits {
+        1 => StopBits::STOP1,
+        2 => StopBits::STOP2,
+        _ => return None,
+    };
+
+    Some(line)
+}
+
+/// `None` until [`init`] has run.
+static CONFIG: blocking_mutex::Mutex<CriticalSectionRawMutex, RefCell<Option<Config>>> =
+    blocking_mutex::Mutex::new(RefCell::new(None));
+
+static STORAGE: OnceLock<Mutex<CriticalSectionRawMutex, Storage>> = OnceLock::new();
+
+/// Load the stored settings, falling back to factory defaults if there are none or they are
+/// unreadable.
+pub(crate) fn init(mut storage: Storage) {
+    let mut record = [0; MAX_RECORD_SIZE];
+
+    let config = match storage.read(&mut record) {
+        Some(len) => {
+            let config = Config::decode(&record[..len]);
+            if config.is_none() {
+                warn!("Stored settings are invalid, using factory defaults");
+            }
+            config
+        }
+        None => {
+            info!("No stored settings, using factory defaults");
+            None
+        }
+    };
+
+    CONFIG.lock(|c| c.replace(Some(config.unwrap_or_else(Config::factory_default))));
+
+    let _ = STORAGE.init(Mutex::new(storage));
+}
+
+/// The current settings.
+pub(crate) fn get() -> Config {
+    CONFIG
+        .lock(|c| c.borrow().clone())
+        .unwrap_or_else(Config::factory_default)
+}
+
+/// Change and store the settings, they take effect once each module next reads them.
+pub(crate) async fn update(f: impl FnOnce(&mut Config)) -> Result<(), storage::Error> {
+    let mut storage = STORAGE.get().await.lock().await;
+
+    let mut config = get();
+    f(&mut config);
+
+    storage.write(&config.encode())?;
+    CONFIG.lock(|c| c.replace(Some(config)));
+    info!("Settings saved");
+    Ok(())
+}
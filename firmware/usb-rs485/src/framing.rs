@@ -0,0 +1,119 @@
+//! Grouping of received bytes into frames separated by idle time on the line.
+//!
+//! The UART only reports received data once its FIFO is half full or the line has been idle for
+//! around three characters, so gaps shorter than that cannot be detected.
+
+use defmt::Format;
+use embassy_rp::uart::{BufferedUartRx, Config, DataBits, Error, Parity, StopBits};
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::Read;
+
+/// Idle time on the line that ends a frame.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum IdleGap {
+    /// Tenths of a character time, e.g. 35 for the Modbus RTU t3.5 gap.
+    CharTimes(u16),
+    /// Fixed time in microseconds.
+    Micros(u32),
+}
+
+impl IdleGap {
+    pub(crate) fn duration(&self, char_time: Duration) -> Duration {
+        match self {
+            IdleGap::CharTimes(tenths) => char_time * *tenths as u32 / 10,
+            IdleGap::Micros(micros) => Duration::from_micros(*micros as u64),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) struct Framing {
+    pub(crate) idle_gap: IdleGap,
+    /// A frame is ended early once it reaches this length.
+    pub(crate) max_len: usize,
+}
+
+/// Encoded framing as used by the vendor request and stored settings.
+///
+/// The idle gap kind (0 for no framing, 1 for tenths of a character time, 2 for microseconds), the
+/// idle gap as a little endian `u32` and the maximum frame length as a little endian `u16`.
+pub(crate) type FramingBytes = [u8; 7];
+
+pub(crate) fn to_bytes(framing: Option<Framing>) -> FramingBytes {
+    let (kind, gap, max_len) = match framing {
+        None => (0, 0, 0),
+        Some(Framing {
+            idle_gap: IdleGap::CharTimes(tenths),
+            max_len,
+        }) => (1, tenths as u32, max_len),
+        Some(Framing {
+            idle_gap: IdleGap::Micros(micros),
+            max_len,
+        }) => (2, micros, max_len),
+    };
+
+    let mut bytes = [0; 7];
+    bytes[0] = kind;
+    bytes[1..5].copy_from_slice(&gap.to_le_bytes());
+    bytes[5..7].copy_from_slice(&(max_len.min(u16::MAX as usize) as u16).to_le_bytes());
+    bytes
+}
+
+/// Returns `None` if the idle gap kind is unknown.
+pub(crate) fn from_bytes(bytes: FramingBytes) -> Option<Option<Framing>> {
+    let gap = u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]);
+    let max_len = u16::from_le_bytes([bytes[5], bytes[6]]) as usize;
+
+    let idle_gap = match bytes[0] {
+        0 => return Some(None),
+        1 => IdleGap::CharTimes(gap.min(u16::MAX as u32) as u16),
+        2 => IdleGap::Micros(gap),
+        _ => return None,
+    };
+
+    Some(Some(Framing { idle_gap, max_len }))
+}
+
+/// Read bytes until the line has been idle for the framing gap or the frame is full.
+///
+/// Frames containing receive errors are discarded and the error returned.
+pub(crate) async fn read_frame(
+    rx: &mut BufferedUartRx,
+    buf: &mut [u8],
+    framing: &Framing,
+    char_time: Duration,
+) -> Result<usize, Error> {
+    let max_len = framing.max_len.clamp(1, buf.len());
+    let idle_gap = framing.idle_gap.duration(char_time);
+
+    let mut len = rx.read(&mut buf[..max_len]).await?;
+
+    while len < max_len {
+        match with_timeout(idle_gap, rx.read(&mut buf[len..max_len])).await {
+            Ok(n) => len += n?,
+            Err(_) => break,
+        }
+    }
+
+    Ok(len)
+}
+
+/// Time taken to send one character, including start, parity and stop bits.
+pub(crate) fn char_time(config: &Config) -> Duration {
+    let data_bits = match config.data_bits {
+        DataBits::DataBits5 => 5,
+        DataBits::DataBits6 => 6,
+        DataBits::DataBits7 => 7,
+        DataBits::DataBits8 => 8,
+    };
+    let parity_bits = match config.parity {
+        Parity::ParityNone => 0,
+        Parity::ParityEven | Parity::ParityOdd => 1,
+    };
+    let stop_bits = match config.stop_bits {
+        StopBits::STOP1 => 1,
+        StopBits::STOP2 => 2,
+    };
+    let bits = 1 + data_bits + parity_bits + stop_bits;
+    Duration::from_micros(bits * 1_000_000 / config.baudrate as u64)
+}
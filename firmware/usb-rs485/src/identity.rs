@@ -0,0 +1,31 @@
+//! Identifiers unique to each board, derived from the unique ID of its flash chip.
+
+use crate::storage::Storage;
+use core::cell::Cell;
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use heapless::String;
+
+static UNIQUE_ID: Mutex<CriticalSectionRawMutex, Cell<[u8; 8]>> = Mutex::new(Cell::new([0; 8]));
+
+pub(crate) fn init(storage: &mut Storage) {
+    match storage.unique_id() {
+        Ok(id) => {
+            info!("Unique ID: {:02x}", id);
+            UNIQUE_ID.lock(|c| c.set(id));
+        }
+        Err(e) => warn!("Failed to read unique ID: {}", e),
+    }
+}
+
+/// The unique ID in upper case hex.
+pub(crate) fn serial_number() -> String<16> {
+    const DIGITS: &[u8; 16] = b"0123456789ABCDEF";
+
+    let mut serial = String::new();
+    for byte in UNIQUE_ID.lock(Cell::get) {
+        let _ = serial.push(DIGITS[(byte >> 4) as usize] as char);
+        let _ = serial.push(DIGITS[(byte & 0xf) as usize] as char);
+    }
+    serial
+}
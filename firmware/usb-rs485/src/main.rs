@@ -1,21 +1,27 @@
 #![no_std]
 #![no_main]
 
+mod config;
+mod framing;
+mod identity;
 mod rs485;
+mod stats;
+mod storage;
 mod usb;
 
+use core::cell::Cell;
 use defmt::info;
 use defmt_rtt as _;
 use embassy_executor::Spawner;
-use embassy_rp::{
-    peripherals,
-    uart::{Config, DataBits, Parity, StopBits},
-    Peri,
+use embassy_rp::{peripherals, uart::Config, Peri};
+use embassy_sync::{
+    blocking_mutex::{raw::CriticalSectionRawMutex, Mutex},
+    pipe::Pipe,
+    signal::Signal,
 };
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, pubsub::PubSubChannel};
-use heapless::Vec;
+use framing::Framing;
 use panic_probe as _;
-use portable_atomic as _;
+use portable_atomic::AtomicBool;
 
 assign_resources::assign_resources! {
     rs485_uart_0: Rs485Uart0Resources {
@@ -31,6 +37,9 @@ assign_resources::assign_resources! {
     usb: UsbResources {
         usb: USB,
     },
+    storage: StorageResources {
+        flash: FLASH,
+    },
 }
 
 #[embassy_executor::main]
@@ -39,24 +48,51 @@ async fn main(spawner: Spawner) {
     let r = split_resources!(p);
 
     info!("Hello, world!");
+    stats::init();
 
-    let uart_config = {
-        let mut config = Config::default();
-        config.baudrate = 19200;
-        config.data_bits = DataBits::DataBits8;
-        config.parity = Parity::ParityNone;
-        config.stop_bits = StopBits::STOP1;
-        config
-    };
+    let mut storage = storage::Storage::new(r.storage.flash);
+    identity::init(&mut storage);
+    config::init(storage);
 
     spawner.must_spawn(usb::task(spawner, r.usb));
-    spawner.must_spawn(rs485::usb_task(spawner, r.rs485_uart_0, uart_config));
-    spawner.must_spawn(rs485::echo_task(r.rs485_uart_1, uart_config));
+    spawner.must_spawn(rs485::task(r.rs485_uart_0, r.rs485_uart_1));
 }
 
-pub(crate) type Payload = Vec<u8, 64>;
+/// Number of RS485 ports, each is presented as its own USB serial port.
+pub(crate) const PORT_COUNT: usize = 2;
+
+/// Bytes buffered in each direction between USB and a UART.
+///
+/// Writers wait when a pipe is full, so a slow line holds off the host and vice versa.
+const PIPE_SIZE: usize = 1024;
+
+type BytePipe = Pipe<CriticalSectionRawMutex, PIPE_SIZE>;
+
+pub(crate) static USB_TO_RS485: [BytePipe; PORT_COUNT] = [const { Pipe::new() }; PORT_COUNT];
+pub(crate) static RS485_TO_USB: [BytePipe; PORT_COUNT] = [const { Pipe::new() }; PORT_COUNT];
+
+/// Whether the host has each USB serial port open, as shown by it raising DTR.
+pub(crate) static USB_CONNECTED: [AtomicBool; PORT_COUNT] =
+    [const { AtomicBool::new(false) }; PORT_COUNT];
+
+/// Line settings requested by the USB host.
+pub(crate) static USB_LINE_CODING: [Signal<CriticalSectionRawMutex, Config>; PORT_COUNT] =
+    [const { Signal::new() }; PORT_COUNT];
+
+/// Port modes requested by the USB host.
+pub(crate) static PORT_MODE: [Signal<CriticalSectionRawMutex, rs485::Mode>; PORT_COUNT] =
+    [const { Signal::new() }; PORT_COUNT];
+
+/// Framing applied to data received on each UART, `None` passes data on as it arrives.
+pub(crate) static PORT_FRAMING: [Mutex<CriticalSectionRawMutex, Cell<Option<Framing>>>;
+    PORT_COUNT] = [const { Mutex::new(Cell::new(None)) }; PORT_COUNT];
+
+/// Requests from the USB host to store the current settings of each port.
+pub(crate) static SAVE_SETTINGS: [Signal<CriticalSectionRawMutex, ()>; PORT_COUNT] =
+    [const { Signal::new() }; PORT_COUNT];
 
-pub(crate) static USB_TO_RS485: PubSubChannel<CriticalSectionRawMutex, Payload, 8, 1, 1> =
-    PubSubChannel::new();
-pub(crate) static RS485_TO_USB: PubSubChannel<CriticalSectionRawMutex, Payload, 8, 1, 1> =
-    PubSubChannel::new();
+/// Serial number requested by the USB host, empty to go back to the one derived from the unique ID.
+pub(crate) static SET_SERIAL_NUMBER: Signal<
+    CriticalSectionRawMutex,
+    heapless::String<{ config::MAX_SERIAL_NUMBER_LEN }>,
+> = Signal::new();
@@ -1,17 +1,34 @@
-use crate::{Rs485Uart0Resources, Rs485Uart1Resources};
-use defmt::{debug, info, warn};
-use embassy_executor::Spawner;
+use crate::{
+    config::{self, PortConfig},
+    framing, stats, Rs485Uart0Resources, Rs485Uart1Resources,
+};
+use core::cell::Cell;
+use defmt::{debug, info, warn, Format};
+use embassy_futures::{
+    join::join,
+    select::{select, select3, Either3},
+};
 use embassy_rp::{
     bind_interrupts,
+    interrupt::typelevel::Binding,
     peripherals::{UART0, UART1},
-    uart::{BufferedInterruptHandler, BufferedUart, BufferedUartRx, BufferedUartTx, Config},
+    uart::{
+        BufferedInterruptHandler, BufferedUart, BufferedUartRx, BufferedUartTx, Config, Instance,
+        RxPin, TxPin,
+    },
+    Peri,
 };
-use embassy_sync::pubsub::WaitResult;
 use embedded_io_async::{Read, Write};
-use heapless::Vec;
+use portable_atomic::Ordering;
 use static_cell::StaticCell;
 
-use super::{RS485_TO_USB, USB_TO_RS485};
+use super::{
+    PORT_FRAMING, PORT_MODE, RS485_TO_USB, SAVE_SETTINGS, USB_CONNECTED, USB_LINE_CODING,
+    USB_TO_RS485,
+};
+
+/// Largest frame or chunk of data read from a UART at once.
+const READ_SIZE: usize = 256;
 
 bind_interrupts!(struct IrqsUart0 {
     UART0_IRQ  => BufferedInterruptHandler<UART0>;
@@ -21,84 +38,194 @@ bind_interrupts!(struct IrqsUart1 {
     UART1_IRQ  => BufferedInterruptHandler<UART1>;
 });
 
-#[embassy_executor::task]
-pub(super) async fn usb_task(spawner: Spawner, r: Rs485Uart0Resources, config: Config) {
-    const TX_BUFFER_SIZE: usize = 32;
-    const RX_BUFFER_SIZE: usize = 32;
-
-    static TX_BUFFER: StaticCell<[u8; TX_BUFFER_SIZE]> = StaticCell::new();
-    let tx_buf = &mut TX_BUFFER.init([0; TX_BUFFER_SIZE])[..];
-
-    static RX_BUFFER: StaticCell<[u8; RX_BUFFER_SIZE]> = StaticCell::new();
-    let rx_buf = &mut RX_BUFFER.init([0; RX_BUFFER_SIZE])[..];
-
-    let uart = BufferedUart::new(
-        r.uart, r.tx_pin, r.rx_pin, IrqsUart0, tx_buf, rx_buf, config,
-    );
-
-    let (tx, rx) = uart.split();
-
-    spawner.must_spawn(tx_task(tx));
-    spawner.must_spawn(rx_task(rx));
+/// What a port does with the data it receives.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum Mode {
+    /// Data is passed between the line and the USB serial port.
+    Bridge,
+    /// Data received on the line is sent straight back, the USB serial port is unused.
+    Echo,
 }
 
 #[embassy_executor::task]
-async fn tx_task(mut tx: BufferedUartTx) {
-    let mut subscriber = USB_TO_RS485.subscriber().unwrap();
+pub(super) async fn task(r0: Rs485Uart0Resources, r1: Rs485Uart1Resources) {
+    const TX_BUFFER_SIZE: usize = 256;
+    const RX_BUFFER_SIZE: usize = 256;
+
+    static TX_BUFFER_0: StaticCell<[u8; TX_BUFFER_SIZE]> = StaticCell::new();
+    let tx_buf_0 = &mut TX_BUFFER_0.init([0; TX_BUFFER_SIZE])[..];
+
+    static RX_BUFFER_0: StaticCell<[u8; RX_BUFFER_SIZE]> = StaticCell::new();
+    let rx_buf_0 = &mut RX_BUFFER_0.init([0; RX_BUFFER_SIZE])[..];
+
+    static TX_BUFFER_1: StaticCell<[u8; TX_BUFFER_SIZE]> = StaticCell::new();
+    let tx_buf_1 = &mut TX_BUFFER_1.init([0; TX_BUFFER_SIZE])[..];
+
+    static RX_BUFFER_1: StaticCell<[u8; RX_BUFFER_SIZE]> = StaticCell::new();
+    let rx_buf_1 = &mut RX_BUFFER_1.init([0; RX_BUFFER_SIZE])[..];
+
+    join(
+        run(
+            0, r0.uart, r0.tx_pin, r0.rx_pin, IrqsUart0, tx_buf_0, rx_buf_0,
+        ),
+        run(
+            1, r1.uart, r1.tx_pin, r1.rx_pin, IrqsUart1, tx_buf_1, rx_buf_1,
+        ),
+    )
+    .await;
+}
+
+/// Run a port, recreating the UART whenever the host changes its settings.
+async fn run<T: Instance>(
+    n: usize,
+    mut uart: Peri<'static, T>,
+    mut tx_pin: Peri<'static, impl TxPin<T>>,
+    mut rx_pin: Peri<'static, impl RxPin<T>>,
+    irq: impl Binding<T::Interrupt, BufferedInterruptHandler<T>> + Copy,
+    tx_buf: &mut [u8],
+    rx_buf: &mut [u8],
+) {
+    let stored = config::get().ports[n];
+    let mut config = stored.line;
+    let mut mode = stored.mode;
+    PORT_FRAMING[n].lock(|f| f.set(stored.framing));
 
     loop {
-        match subscriber.next_message().await {
-            WaitResult::Lagged(_) => {
-                warn!("Subscriber lagged");
-            }
-            WaitResult::Message(msg) => {
-                if let Err(e) = tx.write(&msg).await {
-                    warn!("Failed writing to UART: {}", e);
+        info!("UART {} config: {} baud, {}", n, config.baudrate, mode);
+
+        let uart = BufferedUart::new(
+            uart.reborrow(),
+            tx_pin.reborrow(),
+            rx_pin.reborrow(),
+            irq,
+            &mut *tx_buf,
+            &mut *rx_buf,
+            config,
+        );
+
+        let (mut tx, mut rx) = uart.split();
+
+        let io = async {
+            match mode {
+                Mode::Bridge => {
+                    join(tx_loop(n, &mut tx), rx_loop(n, &mut rx, &config)).await;
                 }
+                Mode::Echo => echo_loop(n, &mut tx, &mut rx, &config).await,
             }
+        };
+        let io = select(io, save_settings(n, config, mode));
+
+        match select3(io, config_changed(n, config), PORT_MODE[n].wait()).await {
+            Either3::First(_) => {}
+            Either3::Second(new_config) => config = new_config,
+            Either3::Third(new_mode) => mode = new_mode,
         }
     }
 }
 
-#[embassy_executor::task]
-async fn rx_task(mut rx: BufferedUartRx) {
-    let publisher = RS485_TO_USB.publisher().unwrap();
+async fn config_changed(n: usize, current: Config) -> Config {
+    loop {
+        let config = USB_LINE_CODING[n].wait().await;
+        if config != current {
+            return config;
+        }
+    }
+}
+
+/// Store the settings the port is running with whenever the host asks.
+async fn save_settings(n: usize, line: Config, mode: Mode) {
+    loop {
+        SAVE_SETTINGS[n].wait().await;
+
+        let framing = PORT_FRAMING[n].lock(Cell::get);
+        let port = PortConfig {
+            line,
+            mode,
+            framing,
+        };
 
+        if let Err(e) = config::update(|config| config.ports[n] = port).await {
+            warn!("Failed to save port {} settings: {}", n, e);
+        }
+    }
+}
+
+async fn tx_loop(n: usize, tx: &mut BufferedUartTx) {
     let mut buf = [0u8; 64];
 
     loop {
-        let n = rx.read(&mut buf).await.unwrap();
-        debug!("Read {} bytes on UART", n);
-
-        let data = &buf[..n];
-        info!("RS485->USB: {:x}", data);
+        let len = USB_TO_RS485[n].read(&mut buf).await;
 
-        let vec = Vec::from_slice(data).unwrap();
-        publisher.publish(vec).await;
+        match tx.write_all(&buf[..len]).await {
+            Ok(()) => stats::PORTS[n].record_tx(len),
+            Err(e) => warn!("Failed writing to UART: {}", e),
+        }
     }
 }
 
-#[embassy_executor::task]
-pub(super) async fn echo_task(r: Rs485Uart1Resources, config: Config) {
-    const TX_BUFFER_SIZE: usize = 32;
-    const RX_BUFFER_SIZE: usize = 32;
+async fn rx_loop(n: usize, rx: &mut BufferedUartRx, config: &Config) {
+    let mut buf = [0u8; READ_SIZE];
 
-    static TX_BUFFER: StaticCell<[u8; TX_BUFFER_SIZE]> = StaticCell::new();
-    let tx_buf = &mut TX_BUFFER.init([0; TX_BUFFER_SIZE])[..];
+    loop {
+        let Some(len) = read(n, rx, &mut buf, config).await else {
+            continue;
+        };
+        debug!("Read {} bytes on UART {}", len, n);
+
+        let data = &buf[..len];
+        info!("RS485->USB: {:x}", data);
 
-    static RX_BUFFER: StaticCell<[u8; RX_BUFFER_SIZE]> = StaticCell::new();
-    let rx_buf = &mut RX_BUFFER.init([0; RX_BUFFER_SIZE])[..];
+        // Nothing drains the pipe while the port is closed
+        if !USB_CONNECTED[n].load(Ordering::Relaxed) {
+            continue;
+        }
 
-    let mut uart = BufferedUart::new(
-        r.uart, r.tx_pin, r.rx_pin, IrqsUart1, tx_buf, rx_buf, config,
-    );
+        // The line cannot be held off, waiting here would only move the loss to the UART FIFO.
+        // Whole chunks are dropped so a frame is never delivered with a piece missing.
+        let pipe = &RS485_TO_USB[n];
+        if pipe.free_capacity() >= len {
+            pipe.write_all(data).await;
+        } else {
+            warn!("USB {} not keeping up, dropped {} bytes", n, len);
+            stats::PORTS[n].record_dropped(len);
+        }
+    }
+}
 
-    let mut buf = [0u8; 64];
+async fn echo_loop(n: usize, tx: &mut BufferedUartTx, rx: &mut BufferedUartRx, config: &Config) {
+    let mut buf = [0u8; READ_SIZE];
 
     loop {
-        let n = uart.read(&mut buf).await.unwrap();
-        debug!("Read {} bytes on UART", n);
-        let data = &buf[..n];
-        uart.write(data).await.unwrap();
+        let Some(len) = read(n, rx, &mut buf, config).await else {
+            continue;
+        };
+        debug!("Read {} bytes on UART {}", len, n);
+
+        match tx.write_all(&buf[..len]).await {
+            Ok(()) => stats::PORTS[n].record_tx(len),
+            Err(e) => warn!("Failed writing to UART: {}", e),
+        }
+    }
+}
+
+/// Read from the UART, grouping data into frames if enabled for the port.
+///
+/// Receive errors are counted rather than returned.
+async fn read(n: usize, rx: &mut BufferedUartRx, buf: &mut [u8], config: &Config) -> Option<usize> {
+    let res = match PORT_FRAMING[n].lock(Cell::get) {
+        Some(framing) => framing::read_frame(rx, buf, &framing, framing::char_time(config)).await,
+        None => rx.read(buf).await,
+    };
+
+    match res {
+        Ok(len) => {
+            stats::PORTS[n].record_rx(len);
+            Some(len)
+        }
+        Err(e) => {
+            warn!("UART {} receive error: {}", n, e);
+            stats::PORTS[n].record_error(e);
+            None
+        }
     }
 }
@@ -0,0 +1,137 @@
+//! Counters of what the board has done since it was reset, for monitoring.
+
+use crate::PORT_COUNT;
+use core::cell::Cell;
+use defmt::{info, Format};
+use embassy_rp::{pac, uart::Error};
+use embassy_sync::blocking_mutex::{raw::CriticalSectionRawMutex, Mutex};
+use embassy_time::Instant;
+use portable_atomic::{AtomicU32, Ordering};
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub(crate) enum ResetReason {
+    PowerOn = 0,
+    RunPin = 1,
+    Debugger = 2,
+    Watchdog = 3,
+}
+
+static RESET_REASON: Mutex<CriticalSectionRawMutex, Cell<ResetReason>> =
+    Mutex::new(Cell::new(ResetReason::PowerOn));
+
+/// Traffic and errors on an RS485 port.
+pub(crate) struct PortStats {
+    rx_bytes: AtomicU32,
+    tx_bytes: AtomicU32,
+    overrun: AtomicU32,
+    break_: AtomicU32,
+    parity: AtomicU32,
+    framing: AtomicU32,
+    /// Received bytes dropped because the host was not reading them quickly enough.
+    dropped_bytes: AtomicU32,
+    /// Times the host opened the USB serial port, seen as DTR being raised.
+    usb_opens: AtomicU32,
+}
+
+impl PortStats {
+    const fn new() -> Self {
+        Self {
+            rx_bytes: AtomicU32::new(0),
+            tx_bytes: AtomicU32::new(0),
+            overrun: AtomicU32::new(0),
+            break_: AtomicU32::new(0),
+            parity: AtomicU32::new(0),
+            framing: AtomicU32::new(0),
+            dropped_bytes: AtomicU32::new(0),
+            usb_opens: AtomicU32::new(0),
+        }
+    }
+
+    pub(crate) fn record_rx(&self, len: usize) {
+        self.rx_bytes.fetch_add(len as u32, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_tx(&self, len: usize) {
+        self.tx_bytes.fetch_add(len as u32, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_error(&self, error: Error) {
+        let counter = match error {
+            Error::Overrun => &self.overrun,
+            Error::Break => &self.break_,
+            Error::Parity => &self.parity,
+            Error::Framing => &self.framing,
+            _ => return,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_dropped(&self, len: usize) {
+        self.dropped_bytes.fetch_add(len as u32, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_usb_open(&self) {
+        self.usb_opens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The overrun, break, parity and framing error counts followed by the dropped byte count.
+    pub(crate) fn errors_to_bytes(&self) -> [u8; 20] {
+        to_bytes([
+            load(&self.overrun),
+            load(&self.break_),
+            load(&self.parity),
+            load(&self.framing),
+            load(&self.dropped_bytes),
+        ])
+    }
+
+    /// The received and sent byte counts and the number of times the port was opened, followed by
+    /// the uptime in seconds and the [`ResetReason`].
+    pub(crate) fn traffic_to_bytes(&self) -> [u8; 20] {
+        to_bytes([
+            load(&self.rx_bytes),
+            load(&self.tx_bytes),
+            load(&self.usb_opens),
+            Instant::now().as_secs() as u32,
+            reset_reason() as u32,
+        ])
+    }
+}
+
+fn load(counter: &AtomicU32) -> u32 {
+    counter.load(Ordering::Relaxed)
+}
+
+/// Values as little endian `u32`s.
+fn to_bytes(values: [u32; 5]) -> [u8; 20] {
+    let mut bytes = [0; 20];
+    for (chunk, value) in bytes.chunks_exact_mut(4).zip(values) {
+        chunk.copy_from_slice(&value.to_le_bytes());
+    }
+    bytes
+}
+
+pub(crate) static PORTS: [PortStats; PORT_COUNT] = [const { PortStats::new() }; PORT_COUNT];
+
+/// Find out why the board was last reset, which must be done once at power on.
+pub(crate) fn init() {
+    let watchdog = pac::WATCHDOG.reason().read();
+    let chip = pac::VREG_AND_CHIP_RESET.chip_reset().read();
+
+    let reason = if watchdog.timer() || watchdog.force() {
+        ResetReason::Watchdog
+    } else if chip.had_psm_restart() {
+        ResetReason::Debugger
+    } else if chip.had_run() {
+        ResetReason::RunPin
+    } else {
+        ResetReason::PowerOn
+    };
+
+    info!("Reset reason: {}", reason);
+    RESET_REASON.lock(|c| c.set(reason));
+}
+
+pub(crate) fn reset_reason() -> ResetReason {
+    RESET_REASON.lock(Cell::get)
+}
@@ -1,30 +1,50 @@
-use crate::{UsbResources, RS485_TO_USB, USB_TO_RS485};
+use crate::{
+    config, framing, identity, rs485::Mode, stats, UsbResources, PORT_COUNT, PORT_FRAMING,
+    PORT_MODE, RS485_TO_USB, SAVE_SETTINGS, SET_SERIAL_NUMBER, USB_CONNECTED, USB_LINE_CODING,
+    USB_TO_RS485,
+};
 use defmt::{debug, info, warn};
 use embassy_executor::Spawner;
 use embassy_futures::select::{select, Either};
 use embassy_rp::{
     bind_interrupts,
     peripherals::USB,
+    uart::{self, DataBits, Parity},
     usb::{Driver, Instance, InterruptHandler},
 };
-use embassy_sync::pubsub::WaitResult;
 use embassy_usb::{
-    class::cdc_acm::{CdcAcmClass, State},
+    class::cdc_acm::{
+        CdcAcmClass, ControlChanged, LineCoding, ParityType, Receiver, Sender, State, StopBits,
+    },
+    control::{InResponse, OutResponse, Recipient, Request, RequestType},
     driver::EndpointError,
-    Config, UsbDevice,
+    Config, Handler, UsbDevice,
 };
-use heapless::Vec;
+use heapless::String;
+use portable_atomic::Ordering;
 use static_cell::StaticCell;
 
 #[embassy_executor::task]
 pub(super) async fn task(spawner: Spawner, r: UsbResources) {
     let usb_driver = Driver::new(r.usb, Irqs);
 
+    let serial_number = {
+        static SERIAL_NUMBER: StaticCell<String<{ config::MAX_SERIAL_NUMBER_LEN }>> =
+            StaticCell::new();
+        let serial_number = config::get().serial_number.unwrap_or_else(|| {
+            let mut serial_number = String::new();
+            let _ = serial_number.push_str(&identity::serial_number());
+            serial_number
+        });
+        info!("Serial number: {}", serial_number);
+        SERIAL_NUMBER.init(serial_number).as_str()
+    };
+
     let usb_config = {
         let mut config = Config::new(0xc0de, 0xcafe);
         config.manufacturer = Some("dannixon");
         config.product = Some("USB-RS485 on pi485");
-        config.serial_number = Some("12345678");
+        config.serial_number = Some(serial_number);
         config.max_power = 100;
         config.max_packet_size_0 = 64;
         config
@@ -45,21 +65,56 @@ pub(super) async fn task(spawner: Spawner, r: UsbResources) {
         )
     };
 
-    let mut usb_class = {
-        static STATE: StaticCell<State> = StaticCell::new();
-        let state = STATE.init(State::new());
-        CdcAcmClass::new(&mut usb_builder, state, 64)
+    let usb_classes = {
+        static STATE: StaticCell<[State; PORT_COUNT]> = StaticCell::new();
+        let states = STATE.init([const { State::new() }; PORT_COUNT]);
+        states
+            .each_mut()
+            .map(|state| CdcAcmClass::new(&mut usb_builder, state, 64))
     };
 
+    {
+        static VENDOR_HANDLER: StaticCell<VendorHandler> = StaticCell::new();
+        usb_builder.handler(VENDOR_HANDLER.init(VendorHandler {}));
+    }
+
     let usb = usb_builder.build();
 
     spawner.must_spawn(usb_task(usb));
 
+    for (n, usb_class) in usb_classes.into_iter().enumerate() {
+        spawner.must_spawn(cdc_task(n, usb_class));
+    }
+
+    loop {
+        let serial_number = SET_SERIAL_NUMBER.wait().await;
+        let serial_number = (!serial_number.is_empty()).then_some(serial_number);
+        info!("Serial number override: {}", serial_number);
+
+        if let Err(e) = config::update(|config| config.serial_number = serial_number).await {
+            warn!("Failed to save serial number: {}", e);
+        }
+    }
+}
+
+#[embassy_executor::task(pool_size = PORT_COUNT)]
+async fn cdc_task(n: usize, usb_class: CdcAcmClass<'static, MyUsbDriver>) {
+    let (mut sender, mut receiver, control) = usb_class.split_with_control();
+
     loop {
-        usb_class.wait_connection().await;
-        info!("Connected");
-        let _ = echo(&mut usb_class).await;
-        info!("Disconnected");
+        // The endpoints are enabled once the device is configured, whether or not the host has the
+        // port open
+        sender.wait_connection().await;
+        info!("Port {} connected", n);
+
+        let _ = select(
+            usb_to_line(n, &mut receiver, &control),
+            line_to_usb(n, &mut sender),
+        )
+        .await;
+
+        USB_CONNECTED[n].store(false, Ordering::Relaxed);
+        info!("Port {} disconnected", n);
     }
 }
 
@@ -75,44 +130,244 @@ async fn usb_task(mut usb: MyUsbDevice) -> ! {
     usb.run().await
 }
 
+/// Vendor request to set the mode of a port, `value` is the mode and `index` is the port number.
+const REQUEST_SET_MODE: u8 = 0x01;
+
+/// Vendor request to read the UART error counters of a port, `index` is the port number.
+///
+/// The response is the overrun, break, parity and framing error counts followed by the number of
+/// received bytes dropped because the host did not read them in time, all as little endian `u32`s.
+const REQUEST_GET_ERRORS: u8 = 0x02;
+
+/// Vendor request to set the framing of data received on a port, `index` is the port number.
+///
+/// The data is described by [`framing::FramingBytes`]. Frames are limited to 256 bytes whatever
+/// the requested maximum.
+const REQUEST_SET_FRAMING: u8 = 0x03;
+
+/// Vendor request to keep the current mode, framing and line settings of a port over a power
+/// cycle, `index` is the port number.
+const REQUEST_SAVE_SETTINGS: u8 = 0x04;
+
+/// Vendor request to set the USB serial number, the data is the new serial number in ASCII or
+/// empty to use the one derived from the unique ID. It takes effect from the next power cycle.
+const REQUEST_SET_SERIAL_NUMBER: u8 = 0x05;
+
+/// Vendor request to read the traffic counters of a port, `index` is the port number.
+///
+/// The response is the number of bytes received and sent on the line and the number of times the
+/// host has opened the port by raising DTR, followed by the uptime in seconds and the reason for the last reset
+/// (0 power on, 1 RUN pin, 2 debugger, 3 watchdog), all as little endian `u32`s.
+const REQUEST_GET_STATS: u8 = 0x06;
+
+struct VendorHandler {}
+
+impl Handler for VendorHandler {
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if req.request_type != RequestType::Vendor || req.recipient != Recipient::Device {
+            return None;
+        }
+
+        if req.request == REQUEST_SET_SERIAL_NUMBER {
+            let serial_number = core::str::from_utf8(data)
+                .ok()
+                .filter(|s| s.bytes().all(|b| b.is_ascii_graphic()))
+                .and_then(|s| String::try_from(s).ok());
+
+            return Some(match serial_number {
+                Some(serial_number) => {
+                    SET_SERIAL_NUMBER.signal(serial_number);
+                    OutResponse::Accepted
+                }
+                None => OutResponse::Rejected,
+            });
+        }
+
+        let n = req.index as usize;
+        if n >= PORT_COUNT {
+            return Some(OutResponse::Rejected);
+        }
+
+        match req.request {
+            REQUEST_SET_MODE => {
+                let mode = match req.value {
+                    0 => Mode::Bridge,
+                    1 => Mode::Echo,
+                    _ => return Some(OutResponse::Rejected),
+                };
+
+                info!("Port {} mode: {}", n, mode);
+                PORT_MODE[n].signal(mode);
+                Some(OutResponse::Accepted)
+            }
+            REQUEST_SET_FRAMING => {
+                let Some(framing) = data.try_into().ok().and_then(framing::from_bytes) else {
+                    return Some(OutResponse::Rejected);
+                };
+
+                info!("Port {} framing: {}", n, framing);
+                PORT_FRAMING[n].lock(|f| f.set(framing));
+                Some(OutResponse::Accepted)
+            }
+            REQUEST_SAVE_SETTINGS => {
+                SAVE_SETTINGS[n].signal(());
+                Some(OutResponse::Accepted)
+            }
+            _ => None,
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if req.request_type != RequestType::Vendor || req.recipient != Recipient::Device {
+            return None;
+        }
+
+        let port = stats::PORTS.get(req.index as usize);
+
+        let data = match req.request {
+            REQUEST_GET_ERRORS => port.map(stats::PortStats::errors_to_bytes),
+            REQUEST_GET_STATS => port.map(stats::PortStats::traffic_to_bytes),
+            _ => return None,
+        };
+
+        match data {
+            Some(data) => {
+                let len = data.len().min(buf.len());
+                buf[..len].copy_from_slice(&data[..len]);
+                Some(InResponse::Accepted(&buf[..len]))
+            }
+            None => Some(InResponse::Rejected),
+        }
+    }
+}
+
 struct Disconnected {}
 
 impl From<EndpointError> for Disconnected {
     fn from(val: EndpointError) -> Self {
         match val {
-            EndpointError::BufferOverflow => panic!("Buffer overflow"),
+            EndpointError::BufferOverflow => {
+                warn!("USB buffer overflow");
+                Disconnected {}
+            }
             EndpointError::Disabled => Disconnected {},
         }
     }
 }
 
-async fn echo<'d, T: Instance + 'd>(
-    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+/// Pass data from the host to the UART, waiting while the UART is behind so the host is held off
+/// rather than data being dropped.
+async fn usb_to_line<'d, T: Instance + 'd>(
+    n: usize,
+    receiver: &mut Receiver<'d, Driver<'d, T>>,
+    control: &ControlChanged<'d>,
 ) -> Result<(), Disconnected> {
-    let publisher = USB_TO_RS485.publisher().unwrap();
-    let mut subscriber = RS485_TO_USB.subscriber().unwrap();
-
     let mut buf = [0; 64];
 
+    update_open(n, receiver);
+
     loop {
-        match select(class.read_packet(&mut buf), subscriber.next_message()).await {
-            Either::First(n) => {
-                let n = n.unwrap();
-                debug!("Read {} bytes on UART", n);
+        match select(receiver.read_packet(&mut buf), control.control_changed()).await {
+            Either::First(len) => {
+                let len = len?;
+                debug!("Read {} bytes on USB", len);
 
-                let data = Vec::from_slice(&buf[..n]).unwrap();
+                let data = &buf[..len];
                 info!("USB->RS485: {:x}", data);
 
-                publisher.publish(data).await;
+                USB_TO_RS485[n].write_all(data).await;
             }
-            Either::Second(msg) => match msg {
-                WaitResult::Lagged(_) => {
-                    warn!("Subscriber lagged");
-                }
-                WaitResult::Message(data) => {
-                    class.write_packet(&data).await?;
+            Either::Second(_) => {
+                let line_coding = receiver.line_coding();
+                info!("Port {} line coding: {}", n, line_coding);
+
+                if let Some(config) = uart_config(&line_coding) {
+                    USB_LINE_CODING[n].signal(config);
                 }
-            },
+
+                update_open(n, receiver);
+            }
+        }
+    }
+}
+
+/// Follow the host opening and closing the port, which it signals with DTR.
+fn update_open<'d, T: Instance + 'd>(n: usize, receiver: &Receiver<'d, Driver<'d, T>>) {
+    let open = receiver.dtr();
+    if USB_CONNECTED[n].load(Ordering::Relaxed) == open {
+        return;
+    }
+
+    if open {
+        info!("Port {} opened", n);
+        stats::PORTS[n].record_usb_open();
+        // Data left over from before the port was last closed is of no use to the new reader
+        RS485_TO_USB[n].clear();
+    } else {
+        info!("Port {} closed", n);
+    }
+    USB_CONNECTED[n].store(open, Ordering::Relaxed);
+}
+
+/// Pass data from the UART to the host.
+async fn line_to_usb<'d, T: Instance + 'd>(
+    n: usize,
+    sender: &mut Sender<'d, Driver<'d, T>>,
+) -> Result<(), Disconnected> {
+    let mut buf = [0; 64];
+
+    loop {
+        let len = RS485_TO_USB[n].read(&mut buf).await;
+        sender.write_packet(&buf[..len]).await?;
+
+        // A full packet does not end a transfer, so the host would hold on to the data until more
+        // arrives
+        if len == buf.len() && RS485_TO_USB[n].is_empty() {
+            sender.write_packet(&[]).await?;
         }
     }
 }
+
+/// Convert the line coding set by the host to a UART config, unsupported settings are replaced
+/// with the closest supported ones.
+fn uart_config(line_coding: &LineCoding) -> Option<uart::Config> {
+    if line_coding.data_rate() == 0 {
+        return None;
+    }
+
+    let mut config = uart::Config::default();
+
+    config.baudrate = line_coding.data_rate();
+
+    config.data_bits = match line_coding.data_bits() {
+        5 => DataBits::DataBits5,
+        6 => DataBits::DataBits6,
+        7 => DataBits::DataBits7,
+        8 => DataBits::DataBits8,
+        bits => {
+            warn!("Unsupported data bits: {}", bits);
+            DataBits::DataBits8
+        }
+    };
+
+    config.parity = match line_coding.parity_type() {
+        ParityType::None => Parity::ParityNone,
+        ParityType::Odd => Parity::ParityOdd,
+        ParityType::Even => Parity::ParityEven,
+        parity => {
+            warn!("Unsupported parity: {}", parity);
+            Parity::ParityNone
+        }
+    };
+
+    config.stop_bits = match line_coding.stop_bits() {
+        StopBits::One => uart::StopBits::STOP1,
+        StopBits::Two => uart::StopBits::STOP2,
+        stop_bits => {
+            warn!("Unsupported stop bits: {}", stop_bits);
+            uart::StopBits::STOP1
+        }
+    };
+
+    Some(config)
+}